@@ -1,34 +1,48 @@
-#![feature(collections)]
 #[macro_use]
 extern crate log;
 extern crate env_logger;
 extern crate parser_combinators;
 
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::fmt;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
 // TODO: 
 //     - Benchmarks
 //     - Cache of last piece
 //     - merge consecutive insert, delete
-//     - snapshots
 //     - Allow String, &str, &[u8], and Vec<u8> as parameter to insert, append
 
+/// Type of offsets and lengths into a Text.
+pub type Offset = usize;
+
 /// A append only buffer
 /// (This is unnecessary inefficient because we copy, we could
 /// just allocate new separate buffers)
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AppendOnlyBuffer {
     buf: Vec<u8>,
 } 
 
-#[derive(Debug,Copy,Clone,PartialEq)]
+/// The bytes from off1 (inclusive) to off2 (exclusive).  Spans are
+/// ordered by off1, then off2 (the derived order, so keep the fields in
+/// this order).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Span {
-    off1: u32,
-    off2: u32,
+    off1: Offset,
+    off2: Offset,
 } 
 
 impl Span {
-    pub fn new(off1: u32, off2: u32) -> Span {
+    pub fn new(off1: Offset, off2: Offset) -> Span {
         assert!(off2 >= off1);
-        Span { off1: off1, off2: off2 }
+        Span { off1, off2 }
     } 
 
     /// The empty span 
@@ -36,7 +50,17 @@ impl Span {
         Span::new(0,0)
     } 
 
-    pub fn len(&self) -> u32 {
+    /// First offset in the span
+    pub fn off1(&self) -> Offset {
+        self.off1
+    } 
+
+    /// Offset behind the span
+    pub fn off2(&self) -> Offset {
+        self.off2
+    } 
+
+    pub fn len(&self) -> Offset {
         self.off2 - self.off1 
     } 
 
@@ -45,36 +69,151 @@ impl Span {
     }
 
     /// Split self such that the left piece has n characters.
-    pub fn split(&self, n: u32) -> Option<(Span, Span)> {
+    pub fn split(&self, n: Offset) -> Option<(Span, Span)> {
         if n == 0 || n == self.len() {
             None
         } else {
             Some((Span::new(self.off1, self.off1+n), Span::new(self.off1+n, self.off2)))
         } 
     } 
+
+    /// Split self such that the left part has n bytes, telling apart the
+    /// splits at the ends: (None, Some(self)) for n == 0 (even when self
+    /// is empty), (Some(self), None) for n == len and an error for n > len.
+    pub fn split_checked(&self, n: Offset) -> Result<(Option<Span>, Option<Span>), OffsetError> {
+        if n == 0 {
+            Ok((None, Some(*self)))
+        } else if n == self.len() {
+            Ok((Some(*self), None))
+        } else if n < self.len() {
+            Ok((Some(Span::new(self.off1, self.off1 + n)), Some(Span::new(self.off1 + n, self.off2))))
+        } else {
+            Err(OffsetError::OutOfBounds { off: n, len: self.len() })
+        } 
+    } 
+
+    /// Is off1 <= off < off2?
+    pub fn contains(&self, off: Offset) -> bool {
+        self.off1 <= off && off < self.off2
+    } 
+
+    /// The bytes covered by both self and other.  None if they
+    /// have no byte in common (this includes touching spans).
+    pub fn intersect(&self, other: &Span) -> Option<Span> {
+        let off1 = std::cmp::max(self.off1, other.off1);
+        let off2 = std::cmp::min(self.off2, other.off2);
+        if off1 < off2 {
+            Some(Span::new(off1, off2))
+        } else {
+            None
+        } 
+    } 
+
+    /// The span covering self and other, if they overlap or touch.
+    /// None if there is a gap between them.
+    pub fn try_merge(&self, other: &Span) -> Option<Span> {
+        if self.off1 <= other.off2 && other.off1 <= self.off2 {
+            Some(Span::new(std::cmp::min(self.off1, other.off1),
+                           std::cmp::max(self.off2, other.off2)))
+        } else {
+            None
+        } 
+    } 
 } 
 
 impl AppendOnlyBuffer {
     /// Constructs a new, empty AppendOnlyBuffer.
     pub fn new() -> AppendOnlyBuffer {
+        AppendOnlyBuffer::with_capacity(4096)
+    }
+
+    /// Constructs a new, empty AppendOnlyBuffer with room for cap bytes.
+    pub fn with_capacity(cap: usize) -> AppendOnlyBuffer {
         AppendOnlyBuffer {
-          buf: Vec::with_capacity(4096)
+          buf: Vec::with_capacity(cap)
         } 
     }
 
-    /// Append a slice of bytes.
+    /// Make room for at least additional more bytes.
+    pub fn reserve(&mut self, additional: usize) {
+        self.buf.reserve(additional)
+    } 
+
+    /// Constructs an AppendOnlyBuffer containing the bytes of buf.
+    pub fn from_vec(buf: Vec<u8>) -> AppendOnlyBuffer {
+        AppendOnlyBuffer { buf }
+    } 
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    } 
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    } 
+
+    pub fn capacity(&self) -> usize {
+        self.buf.capacity()
+    } 
+
+    /// The bytes, without copying them.
+    pub fn into_vec(self) -> Vec<u8> {
+        self.buf
+    } 
+
+    /// Forget all bytes, but keep the memory.
+    pub fn clear(&mut self) {
+        self.buf.clear()
+    } 
+
+    /// Append a slice of bytes.  Panics if the buffer would get longer
+    /// than an Offset can address.
     pub fn append(&mut self, bytes: &[u8]) -> Span {
-      let off1 = self.buf.len() as u32;
-      self.buf.push_all(bytes);
-      Span::new(off1, self.buf.len() as u32)
+        self.try_append(bytes).expect("buffer exceeds the Offset range")
+    } 
+
+    /// Append a slice of bytes, unless the buffer would get longer than
+    /// an Offset can address.
+    pub fn try_append(&mut self, bytes: &[u8]) -> Option<Span> {
+        let off2 = end_offset(self.buf.len(), bytes.len())?;
+        let off1 = self.buf.len() as Offset;
+        self.buf.extend_from_slice(bytes);
+        Some(Span::new(off1, off2))
+    } 
+
+    /// Append bytes, taking over the Vec instead of copying it when the
+    /// buffer is empty.  Panics as append.
+    pub fn append_owned(&mut self, bytes: Vec<u8>) -> Span {
+        if !self.buf.is_empty() {
+            return self.append(&bytes);
+        } 
+        let off2 = end_offset(0, bytes.len()).expect("buffer exceeds the Offset range");
+        self.buf = bytes;
+        Span::new(0, off2)
     } 
 
     pub fn get(&self, s: Span) -> &[u8] {
-        &self.buf[s.off1 as usize .. s.off2 as usize]
+        &self.buf[index(s.off1) .. index(s.off2)]
+    } 
+
+    pub fn get_byte(&self, p: Offset) -> u8 {
+        self.buf[index(p)]
     } 
 
-    pub fn get_byte(&self, p: u32) -> u8 {
-        self.buf[p as usize]
+    /// Like get, but None if s is not within the buffer
+    pub fn try_get(&self, s: Span) -> Option<&[u8]> {
+        self.buf.get(index(s.off1) .. index(s.off2))
+    } 
+
+    /// Like get_byte, but None if p is not within the buffer
+    pub fn try_get_byte(&self, p: Offset) -> Option<u8> {
+        self.buf.get(index(p)).cloned()
+    } 
+} 
+
+impl Default for AppendOnlyBuffer {
+    fn default() -> AppendOnlyBuffer {
+        AppendOnlyBuffer::new()
     } 
 } 
 
@@ -82,345 +221,5887 @@ impl AppendOnlyBuffer {
 /// them.  That is fine because we never free a piece anyway (unlimited undo
 /// for the win).
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-struct Piece(u32);
+struct Piece(usize);
+
+/// Error for offsets that do not fit the text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OffsetError {
+    /// off is past the end of a text of length len
+    OutOfBounds { off: Offset, len: Offset },
+    /// The range off1..off2 ends before it starts
+    Reversed { off1: Offset, off2: Offset },
+} 
+
+impl fmt::Display for OffsetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OffsetError::OutOfBounds { off, len } =>
+                write!(f, "offset {} is past the end of the text ({} bytes)", off, len),
+            OffsetError::Reversed { off1, off2 } =>
+                write!(f, "range {}..{} ends before it starts", off1, off2),
+        } 
+    } 
+} 
+
+impl std::error::Error for OffsetError {} 
+
+/// How lines end, see Text::normalize_newlines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    /// "\n"
+    Lf,
+    /// "\r\n"
+    CrLf,
+    /// "\r"
+    Cr,
+} 
+
+impl LineEnding {
+    pub fn as_bytes(self) -> &'static [u8] {
+        match self {
+            LineEnding::Lf => b"\n",
+            LineEnding::CrLf => b"\r\n",
+            LineEnding::Cr => b"\r",
+        } 
+    } 
+} 
+
+/// A change to a Text, see Text::diff
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Edit {
+    Insert { off: Offset, bytes: Vec<u8> },
+    Delete { off: Offset, len: Offset },
+} 
+
+/// Error returned by Text::apply_edits: edit number index did not fit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EditError {
+    pub index: usize,
+    pub error: OffsetError,
+} 
+
+impl fmt::Display for EditError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "edit {}: {}", self.index, self.error)
+    } 
+} 
+
+impl std::error::Error for EditError {} 
+
+/// Error for byte offsets in the middle of a UTF-8 sequence (or past
+/// the end of the text).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotCharBoundary {
+    pub off: Offset,
+} 
+
+impl fmt::Display for NotCharBoundary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "offset {} is not a char boundary", self.off)
+    } 
+} 
+
+impl std::error::Error for NotCharBoundary {} 
+
+/// What Text::check_integrity found wrong.  Pieces are identified by
+/// their index, which is only useful for debugging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegrityError {
+    /// A live piece has an empty span
+    EmptyPiece { piece: usize },
+    /// The span of a piece is not within its buffer
+    SpanOutOfBuffer { piece: usize, span: Span, buffer_len: Offset },
+    /// The newline count cached in a piece is wrong
+    NewlineCount { piece: usize, cached: Offset, actual: Offset },
+    /// Walking the list forward (or backward) did not return to the
+    /// sentinel within as many steps as there are pieces
+    Cycle { forward: bool },
+    /// The pieces do not add up to len
+    Length { walked: Offset, len: Offset },
+    /// The backward walk did not visit the pieces of the forward walk
+    /// in reverse
+    WalksDiffer,
+    /// The weight or heap order of a tree node is wrong
+    Tree { piece: usize },
+    /// The tree does not contain the pieces of the list, in order
+    TreeOrder,
+} 
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IntegrityError::EmptyPiece { piece } =>
+                write!(f, "piece {} is empty", piece),
+            IntegrityError::SpanOutOfBuffer { piece, span, buffer_len } =>
+                write!(f, "piece {} spans {}..{} of a buffer of {} bytes",
+                       piece, span.off1, span.off2, buffer_len),
+            IntegrityError::NewlineCount { piece, cached, actual } =>
+                write!(f, "piece {} claims {} newlines but has {}", piece, cached, actual),
+            IntegrityError::Cycle { forward } =>
+                write!(f, "the {} walk does not end", if forward { "forward" } else { "backward" }),
+            IntegrityError::Length { walked, len } =>
+                write!(f, "the pieces cover {} bytes, but len is {}", walked, len),
+            IntegrityError::WalksDiffer =>
+                write!(f, "the forward and backward walks visit different pieces"),
+            IntegrityError::Tree { piece } =>
+                write!(f, "the tree node of piece {} is wrong", piece),
+            IntegrityError::TreeOrder =>
+                write!(f, "the tree does not match the list"),
+        } 
+    } 
+} 
+
+impl std::error::Error for IntegrityError {} 
+
+/// Which buffer the span of a piece refers to, that is where bytes of
+/// the text came from, see Text::segments
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// The original bytes the text was created from
+    Original,
+    /// The append only buffer of inserted bytes
+    Added,
+} 
 
 /// The actual data stored in a piece.  
 /// We have one sentinel piece which is always stored at index 0
 /// in the vector.  It's span is also empty
-#[derive(Debug)]
+/// Besides the doubly linked list the live pieces also form a treap
+/// ordered by position in the text, so that we can find the piece
+/// containing an offset in O(log n).  The list is the truth, the tree
+/// is only an index into it.  The sentinel is never part of the tree
+/// and doubles as the empty tree.
+#[derive(Debug, Clone)]
 struct PieceData {
-    /// Some bytes in the text's buffer
+    /// Some bytes in the text's original or append only buffer
+    source: Source,
     span: Span,
+    /// Number of newlines in span
+    newlines: Offset,
     prev: Piece,
     next: Piece,
+    left: Piece,
+    right: Piece,
+    /// Number of bytes in the subtree rooted at this piece
+    weight: Offset,
+} 
+
+/// The links of a piece before and after an edit.
+#[derive(Debug, Clone)]
+struct LinkChange {
+    piece: Piece,
+    /// (prev, next) before the edit
+    before: (Piece, Piece),
+    /// (prev, next) after the edit
+    after: (Piece, Piece),
+} 
+
+/// Everything we need to undo or redo one edit.  As pieces are never
+/// freed restoring the links of the pieces involved is enough.
+/// The edit replaced removed bytes at off by inserted bytes.
+#[derive(Debug, Clone)]
+struct Change {
+    links: Vec<LinkChange>,
+    off: Offset,
+    removed: Offset,
+    /// Where in the append only buffer the inserted bytes are
+    added: Span,
+    /// Versions of the text before and after the edit
+    before: u64,
+    after: u64,
+    /// Undone and redone together with the change before it, see
+    /// join_changes
+    joined: bool,
+} 
+
+impl Change {
+    /// A structural change relinks pieces without changing the content
+    fn is_structural(&self) -> bool {
+        self.removed == 0 && self.added.is_empty()
+    } 
+
+    fn inserted(&self) -> Offset {
+        self.added.len()
+    } 
+} 
+
+/// Memory usage of a Text, see Text::stats.
+#[derive(Debug, Clone)]
+pub struct TextStats {
+    /// Number of bytes in the text
+    pub logical_len: Offset,
+    /// Bytes allocated for the append only buffer
+    pub buffer_capacity: Offset,
+    /// Bytes in the append only buffer
+    pub buffer_used: Offset,
+    /// Pieces making up the text
+    pub live_pieces: usize,
+    /// Pieces in the pieces vector, including the sentinel and the
+    /// pieces only kept for undo
+    pub total_pieces: usize,
+    /// Bytes in the append only buffer not in the text (compact gets
+    /// rid of them)
+    pub dead_bytes: Offset,
+} 
+
+/// When a Text compacts itself (see Text::compact), set with
+/// Text::set_auto_compact.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompactPolicy {
+    /// Only when compact is called
+    #[default]
+    Off,
+    /// Before an edit, once the pieces vector holds more than four times
+    /// as many pieces as the text is made of or more than half of the
+    /// append only buffer is no longer in the text
+    Auto,
+} 
+
+/// A version of a Text that can be gone back to with Text::restore.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshot {
+    version: u64,
+} 
+
+/// A position in a Text that moves along when the text is edited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Marker(usize);
+
+/// What an edit did to the text, passed to the callback set with
+/// Text::set_on_edit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditEvent {
+    Inserted { off: Offset, len: Offset },
+    Deleted { off: Offset, len: Offset },
+    /// removed bytes at off were replaced by inserted other bytes
+    Replaced { off: Offset, removed: Offset, inserted: Offset },
+} 
+
+/// A callback for Text::set_on_edit
+pub type EditCallback = Box<dyn FnMut(&EditEvent) + Send>;
+
+/// The callback of Text::set_on_edit.  Clones of a text do not get it.
+struct OnEdit(Option<EditCallback>);
+
+impl Clone for OnEdit {
+    fn clone(&self) -> OnEdit {
+        OnEdit(None)
+    } 
+} 
+
+impl fmt::Debug for OnEdit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(if self.0.is_some() { "OnEdit(Some(..))" } else { "OnEdit(None)" })
+    } 
+} 
+
+/// Number of transactions open on a text, see Text::begin.  Clones are
+/// not part of them.
+#[derive(Debug, Default)]
+struct OpenTransactions(usize);
+
+impl Clone for OpenTransactions {
+    fn clone(&self) -> OpenTransactions {
+        OpenTransactions(0)
+    } 
+} 
+
+/// A byte offset in a Text, for the Text::insert_at and delete_at API.
+/// Unlike a plain Offset it cannot be confused with a length, a line
+/// number or a char index.  It does not know which text it belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Pos(pub Offset);
+
+impl Pos {
+    /// The start of any text
+    pub fn start() -> Pos {
+        Pos(0)
+    } 
+
+    /// The end of text
+    pub fn end(text: &Text) -> Pos {
+        Pos(text.len())
+    } 
+
+    /// n bytes further, saturating at Offset::MAX
+    pub fn saturating_add(self, n: Offset) -> Pos {
+        Pos(self.0.saturating_add(n))
+    } 
+
+    /// n bytes back, saturating at the start
+    pub fn saturating_sub(self, n: Offset) -> Pos {
+        Pos(self.0.saturating_sub(n))
+    } 
+} 
+
+impl From<Offset> for Pos {
+    fn from(off: Offset) -> Pos {
+        Pos(off)
+    } 
+} 
+
+impl From<Pos> for Offset {
+    fn from(Pos(off): Pos) -> Offset {
+        off
+    } 
 } 
 
 /// Text is just a sequence of bytes (implemented with the PieceTable method,
 /// ala Oberon).  We on purpose do not require UTF-8 here.  A programmers
 /// editor is most useful when it can deal with any sequence of bytes.
-#[derive(Debug)]
-pub struct Text {
+/// The original bytes (say a memory mapped file) are never copied, only
+/// inserted bytes go into the append only buffer.
+/// Cloning copies the buffer and the pieces (which refer to each other
+/// by index, so no remapping is needed), including the undo history.
+#[derive(Debug, Clone)]
+pub struct Text<'a> {
+    original: Cow<'a, [u8]>,
     buffer: AppendOnlyBuffer,
     pieces: Vec<PieceData>,
-    len: usize,
+    /// Root of the tree of live pieces
+    root: Piece,
+    len: Offset,
+    /// Links changed by the edit in progress
+    pending: Vec<LinkChange>,
+    undo: Vec<Change>,
+    redo: Vec<Change>,
+    /// Offsets of all markers, indexed by Marker
+    markers: Vec<Offset>,
+    /// Every state the text has been in has a different version
+    version: u64,
+    last_version: u64,
+    /// Where in the buffer bytes inserted by insert_interned are
+    interned: HashMap<Vec<u8>, Span>,
+    on_edit: OnEdit,
+    compact_policy: CompactPolicy,
+    /// Size of the pieces vector at which auto_compact looks again
+    compact_check_at: usize,
+    /// Number of edits ever made, see edit_count
+    edit_count: u64,
+    /// auto_compact waits while there are any, the rollback needs the history
+    transactions: OpenTransactions,
 } 
 
 struct Pieces<'a> {
-    text: &'a Text,
+    text: &'a Text<'a>,
     next: Piece,
     /// start position of piece in text
-    off: u32, 
+    off: Offset, 
 } 
 
 impl<'a> Iterator for Pieces<'a> {
-    type Item = (u32, Piece);
+    type Item = (Offset, Piece);
 
-    fn next(&mut self) -> Option<(u32, Piece)> {
+    fn next(&mut self) -> Option<(Offset, Piece)> {
         if self.next == SENTINEL {
             None
         } else {
             let piece = self.next;
             let Piece(p) = piece;
-            let pd = &self.text.pieces[p as usize];
+            let pd = &self.text.pieces[p];
             let off = self.off;
             let span = &pd.span;
-            let next = *&pd.next;
-            self.off = self.off + span.len();
+            let next = pd.next;
+            self.off += span.len();
             self.next = next;
             Some ((off, piece))
         } 
     } 
 } 
 
+/// Iterator over the pieces of a Text as the span of the text they make
+/// up and where their bytes came from, see Text::segments.
+pub struct Segments<'a> {
+    pieces: Pieces<'a>,
+} 
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = (Span, Source);
+
+    fn next(&mut self) -> Option<(Span, Source)> {
+        let (off, p) = self.pieces.next()?;
+        let d = self.pieces.text.get_piece(p);
+        Some((Span::new(off, off + d.span.len()), d.source))
+    } 
+} 
+
+/// Iterator over the bytes of (a range of) a Text, as slices borrowed
+/// from the buffers, one per piece.
+pub struct Chunks<'a> {
+    pieces: Pieces<'a>,
+    off1: Offset,
+    off2: Offset,
+} 
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let text = self.pieces.text;
+        let (s, p) = self.pieces.next()?;
+        if s >= self.off2 {
+            return None;
+        } 
+        let d = text.get_piece(p);
+        let from = self.off1.saturating_sub(s);
+        let to = std::cmp::min(self.off2 - s, d.span.len());
+        Some(text.span_bytes(d.source, Span::new(d.span.off1 + from, d.span.off1 + to)))
+    } 
+} 
+
+/// Iterator over the bytes of a Text in owned blocks of block_size
+/// bytes, only the last one may be shorter.
+pub struct Blocks<'a> {
+    chunks: Chunks<'a>,
+    /// What is left of the chunk we are in
+    rest: &'a [u8],
+    block_size: usize,
+} 
+
+impl<'a> Iterator for Blocks<'a> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let mut block = Vec::with_capacity(self.block_size);
+        while block.len() < self.block_size {
+            if self.rest.is_empty() {
+                match self.chunks.next() {
+                    Some(chunk) => self.rest = chunk,
+                    None => break,
+                } 
+            } 
+            let n = std::cmp::min(self.block_size - block.len(), self.rest.len());
+            block.extend_from_slice(&self.rest[..n]);
+            self.rest = &self.rest[n..];
+        } 
+        if block.is_empty() { None } else { Some(block) }
+    } 
+} 
+
 pub struct Bytes<'a> {
     pieces: Pieces<'a>,
     pd: Option<&'a PieceData>,
     // where we are in the current piece
-    off: u32
+    off: Offset,
+    // number of bytes not yet returned
+    remaining: usize,
 } 
 
 impl<'a> Iterator for Bytes<'a> {
     type Item = u8;
 
     fn next(&mut self) -> Option<u8> {
-        match self.pd {
-            None => None,
-            Some(pd) => {
-                let span = pd.span;
-                if self.off >= span.len() {
-                    self.off = 0;
-                    self.pd = self.pieces.next().map(|(_, p)| self.pieces.text.get_piece(p));
-                    self.next()
-                } else {
-                    let byte = self.pieces.text.buffer.get_byte(span.off1 + self.off);
-                    self.off += 1;
-                    Some(byte)
-                } 
+        while let Some(pd) = self.pd {
+            let span = pd.span;
+            if self.off < span.len() {
+                let byte = self.pieces.text.span_byte(pd.source, span.off1 + self.off);
+                self.off += 1;
+                self.remaining -= 1;
+                return Some(byte);
             } 
+            self.off = 0;
+            self.pd = self.pieces.next().map(|(_, p)| self.pieces.text.get_piece(p));
         } 
+        None
+    } 
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
     } 
 } 
 
-// The sentinel is always stored at position 0 in the pieces vector
-const SENTINEL: Piece = Piece(0);
+impl<'a> ExactSizeIterator for Bytes<'a> {} 
 
-impl Text {
-    pub fn new() -> Text {
-        Text {
-            buffer: AppendOnlyBuffer::new(),
-            pieces: vec![PieceData { 
-                span: Span::empty(),
-                prev: SENTINEL,
-                next: SENTINEL,
-            }],
-            len: 0,
-        } 
-    } 
+/// Iterator over the bytes of a Text together with their offsets, like
+/// str::char_indices.
+pub struct ByteIndices<'a> {
+    bytes: Bytes<'a>,
+    off: Offset,
+} 
 
-    fn invariant(&self) {
-        let mut l = 0;
-        let mut p = self.get_piece(SENTINEL).next;
-        while p != SENTINEL {
-            let len = self.get_piece(p).span.len();
-            assert!(len > 0);
-            l += len;
-            p = self.get_piece(p).next;
-        } 
-        assert_eq!(l as usize, self.len());
+impl<'a> Iterator for ByteIndices<'a> {
+    type Item = (Offset, u8);
 
-        let mut l = 0;
-        let mut p = self.get_piece(SENTINEL).prev;
-        while p != SENTINEL {
-            let len = self.get_piece(p).span.len();
-            assert!(len > 0);
-            l += len;
-            p = self.get_piece(p).prev;
-        } 
-        assert_eq!(l as usize, self.len());
+    fn next(&mut self) -> Option<(Offset, u8)> {
+        let b = self.bytes.next()?;
+        let off = self.off;
+        self.off += 1;
+        Some((off, b))
     } 
 
-    /// Iterator over all pieces (but never the sentinel)
-    fn pieces(&self) -> Pieces {
-        let next = self.get_piece(SENTINEL).next;
-        Pieces {
-            text: self,
-            next: next,
-            off: 0,
-        } 
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.bytes.size_hint()
     } 
+} 
 
-    /// Length of Text in bytes
-    pub fn len(&self) -> usize {
-        self.len
-    } 
+impl<'a> ExactSizeIterator for ByteIndices<'a> {} 
 
-    /// Iterator over all bytes
-    pub fn bytes(&self) -> Bytes {
-        let mut pieces = self.pieces();
-        let pd = pieces.next().map(|(_, p)| self.get_piece(p));
-        Bytes {
-            pieces: pieces,
-            pd: pd,
-            off: 0
+/// Iterator over the bytes of a Text from the end to the beginning.
+pub struct BytesRev<'a> {
+    text: &'a Text<'a>,
+    piece: Piece,
+    // number of bytes of the current piece not yet returned
+    off: Offset,
+    // number of bytes not yet returned
+    remaining: usize,
+} 
+
+impl<'a> Iterator for BytesRev<'a> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        while self.piece != SENTINEL {
+            let pd = self.text.get_piece(self.piece);
+            if self.off > 0 {
+                self.off -= 1;
+                self.remaining -= 1;
+                return Some(self.text.span_byte(pd.source, pd.span.off1 + self.off));
+            } 
+            self.piece = pd.prev;
+            self.off = self.text.get_piece(self.piece).span.len();
         } 
+        None
     } 
 
-    fn get_piece(&self, Piece(p): Piece) -> &PieceData {
-        &self.pieces[p as usize]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
     } 
+} 
 
-    fn link(&mut self, piece1: Piece, piece2: Piece) {
-        let Piece(p1) = piece1;
-        let Piece(p2) = piece2;
-        self.pieces[p1 as usize].next = piece2;
-        self.pieces[p2 as usize].prev = piece1;
+impl<'a> ExactSizeIterator for BytesRev<'a> {} 
+
+/// A sequence of bytes that is not valid UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Utf8Error {
+    buf: [u8; 4],
+    len: u8,
+} 
+
+impl Utf8Error {
+    /// The offending bytes
+    pub fn bytes(&self) -> &[u8] {
+        &self.buf[..self.len as usize]
     } 
+} 
 
-    /// Find the piece containing offset.  Return piece
-    /// and start position of piece in text.
-    /// Will return the sentinel iff off == self.len()
-    /// Returns the right piece if off between two
-    /// pieces
-    fn find_piece(&self, off:u32) -> (u32, Piece) {
-        if off == self.len() as u32 {
-            (off, SENTINEL)
-        } else { 
-            let mut start = 0;
-            let mut piece = SENTINEL;
-            for (s, p) in self.pieces() {
-                if s > off {
-                    // previous piece was the one we wanted
-                    return (start, piece);
+/// Iterator decoding the bytes of a Text as UTF-8.  Invalid sequences
+/// are returned as errors and decoding continues after them.
+pub struct Chars<'a> {
+    bytes: std::iter::Peekable<Bytes<'a>>,
+} 
+
+/// Number of bytes in a UTF-8 sequence starting with b, if b can start one
+fn utf8_sequence_len(b: u8) -> Option<usize> {
+    match b {
+        0x00..=0x7F => Some(1),
+        0xC2..=0xDF => Some(2),
+        0xE0..=0xEF => Some(3),
+        0xF0..=0xF4 => Some(4),
+        _ => None,
+    } 
+} 
+
+fn is_utf8_continuation(b: u8) -> bool {
+    b & 0xC0 == 0x80
+} 
+
+impl<'a> Iterator for Chars<'a> {
+    type Item = Result<char, Utf8Error>;
+
+    fn next(&mut self) -> Option<Result<char, Utf8Error>> {
+        let first = self.bytes.next()?;
+        let mut buf = [first, 0, 0, 0];
+        let mut len = 1;
+        if let Some(n) = utf8_sequence_len(first) {
+            while len < n {
+                match self.bytes.peek() {
+                    Some(&b) if is_utf8_continuation(b) => {
+                        buf[len] = b;
+                        len += 1;
+                        self.bytes.next();
+                    } 
+                    _ => break,
                 } 
-                start = s;
-                piece = p;
-            }
-            return (start, piece);
+            } 
+            if len == n {
+                if let Ok(s) = std::str::from_utf8(&buf[..len]) {
+                    return s.chars().next().map(Ok);
+                } 
+            } 
         } 
+        Some(Err(Utf8Error { buf, len: len as u8 }))
     } 
+} 
 
-    fn add_piece(&mut self, span: Span) -> Piece {
-        self.pieces.push(PieceData { 
-            span: span, 
-            prev: SENTINEL, 
-            next: SENTINEL,
-        } );
-        Piece((self.pieces.len() - 1) as u32)
+/// Iterator over the offsets of the matches of a needle, see Text::find_iter.
+pub struct FindIter<'a, 'b> {
+    text: &'a Text<'a>,
+    needle: &'b [u8],
+    /// Where to look for the next match
+    start: Offset,
+} 
+
+impl<'a, 'b> Iterator for FindIter<'a, 'b> {
+    type Item = Offset;
+
+    fn next(&mut self) -> Option<Offset> {
+        let off = self.text.find_from(self.needle, self.start)?;
+        self.start = off + std::cmp::max(1, self.needle.len() as Offset);
+        Some(off)
     } 
+} 
 
-    /// Delete bytes between off1 (inclusive) and off2 (exclusive)
-    pub fn delete(&mut self, off1: u32, off2: u32) {
-        if off2 <= off1 {
-            return;
+/// Iterator over the lines of a Text, without the newlines.  Like
+/// str::lines a final newline does not start another line.
+pub struct Lines<'a> {
+    bytes: Bytes<'a>,
+} 
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let mut line = Vec::new();
+        match self.bytes.next() {
+            None => return None,
+            Some(b'\n') => return Some(line),
+            Some(b) => line.push(b),
         } 
-        let (lstart, lpiece) = self.find_piece(off1);
-        let lspan = self.get_piece(lpiece).span; 
-        let (rstart, rpiece) = self.find_piece(off2);
-        let rspan = self.get_piece(rpiece).span; 
-        let left = {
-            if let Some((left_span, _right_span)) = lspan.split(off1 - lstart) {
-                let l = self.get_piece(lpiece).prev;
-                let remainder = self.add_piece(left_span);
-                self.link(l, remainder);
-                remainder
-            } else {
-                // We are deleting all of piece
-                assert_eq!(lstart, off1);
-                self.get_piece(lpiece).prev
+        for b in self.bytes.by_ref() {
+            if b == b'\n' {
+                break;
             } 
-        };
-        let right = {
-            if let Some((_left_span, right_span)) = rspan.split(off2 - rstart) {
-                let r = self.get_piece(rpiece).next;
-                let remainder = self.add_piece(right_span);
-                self.link(remainder, r);
-                remainder
-            } else {
-                // We are at the beginning of piece and therefore
-                // won't delete anything of it
-                assert_eq!(rstart, off2);
-                rpiece
+            line.push(b);
+        } 
+        Some(line)
+    } 
+} 
+
+/// Grapheme cluster break property of a char, as far as Graphemes cares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GraphemeCat {
+    Cr,
+    Lf,
+    Control,
+    Extend,
+    Zwj,
+    RegionalIndicator,
+    Pictographic,
+    L,
+    V,
+    T,
+    Lv,
+    Lvt,
+    Other,
+} 
+
+/// Combining marks of common scripts, variation selectors, emoji
+/// modifiers and tags.  Not the full Unicode table.
+const EXTEND: &[(u32, u32)] = &[
+    (0x0300, 0x036F), (0x0483, 0x0489), (0x0591, 0x05BD), (0x05BF, 0x05BF),
+    (0x0610, 0x061A), (0x064B, 0x065F), (0x0670, 0x0670), (0x0900, 0x0902),
+    (0x093A, 0x093A), (0x093C, 0x093C), (0x0941, 0x0948), (0x094D, 0x094D),
+    (0x0E31, 0x0E31), (0x0E34, 0x0E3A), (0x0E47, 0x0E4E), (0x1AB0, 0x1AFF),
+    (0x1DC0, 0x1DFF), (0x200C, 0x200C), (0x20D0, 0x20FF), (0x302A, 0x302F),
+    (0x3099, 0x309A), (0xFE00, 0xFE0F), (0xFE20, 0xFE2F), (0x1F3FB, 0x1F3FF),
+    (0xE0020, 0xE007F), (0xE0100, 0xE01EF),
+];
+
+/// Roughly Extended_Pictographic: the emoji blocks plus the older symbols
+/// commonly presented as emoji.
+const PICTOGRAPHIC: &[(u32, u32)] = &[
+    (0x00A9, 0x00A9), (0x00AE, 0x00AE), (0x203C, 0x203C), (0x2049, 0x2049),
+    (0x2122, 0x2122), (0x2139, 0x2139), (0x2194, 0x2199), (0x21A9, 0x21AA),
+    (0x231A, 0x231B), (0x2328, 0x2328), (0x23CF, 0x23CF), (0x23E9, 0x23F3),
+    (0x23F8, 0x23FA), (0x24C2, 0x24C2), (0x25AA, 0x25AB), (0x25B6, 0x25B6),
+    (0x25C0, 0x25C0), (0x25FB, 0x25FE), (0x2600, 0x27BF), (0x2934, 0x2935),
+    (0x2B05, 0x2B55), (0x3030, 0x3030), (0x303D, 0x303D), (0x3297, 0x3297),
+    (0x3299, 0x3299), (0x1F000, 0x1F0FF), (0x1F10D, 0x1F10F), (0x1F12F, 0x1F12F),
+    (0x1F16C, 0x1F171), (0x1F17E, 0x1F17F), (0x1F18E, 0x1F18E), (0x1F191, 0x1F19A),
+    (0x1F1AD, 0x1F1E5), (0x1F201, 0x1F2FF), (0x1F300, 0x1F3FA), (0x1F400, 0x1FAFF),
+    (0x1FC00, 0x1FFFD),
+];
+
+fn in_ranges(c: u32, ranges: &[(u32, u32)]) -> bool {
+    ranges.binary_search_by(|&(lo, hi)| {
+        if hi < c {
+            std::cmp::Ordering::Less
+        } else if lo > c {
+            std::cmp::Ordering::Greater
+        } else {
+            std::cmp::Ordering::Equal
+        } 
+    }).is_ok()
+} 
+
+fn grapheme_cat(c: char) -> GraphemeCat {
+    let u = c as u32;
+    match u {
+        0x0D => GraphemeCat::Cr,
+        0x0A => GraphemeCat::Lf,
+        0x200D => GraphemeCat::Zwj,
+        0x1F1E6..=0x1F1FF => GraphemeCat::RegionalIndicator,
+        0x1100..=0x115F | 0xA960..=0xA97C => GraphemeCat::L,
+        0x1160..=0x11A7 | 0xD7B0..=0xD7C6 => GraphemeCat::V,
+        0x11A8..=0x11FF | 0xD7CB..=0xD7FB => GraphemeCat::T,
+        0xAC00..=0xD7A3 if (u - 0xAC00).is_multiple_of(28) => GraphemeCat::Lv,
+        0xAC00..=0xD7A3 => GraphemeCat::Lvt,
+        0x2028 | 0x2029 => GraphemeCat::Control,
+        _ if c.is_control() => GraphemeCat::Control,
+        _ if in_ranges(u, EXTEND) => GraphemeCat::Extend,
+        _ if in_ranges(u, PICTOGRAPHIC) => GraphemeCat::Pictographic,
+        _ => GraphemeCat::Other,
+    } 
+} 
+
+/// Iterator over the extended grapheme clusters of a Text, as
+/// (off1, off2) pairs.  Follows the rules of Unicode Standard Annex #29
+/// (so CR LF, combining marks, Hangul syllables, flags and emoji ZWJ
+/// sequences each form one cluster), but with built in approximations
+/// of the character properties instead of the full Unicode tables, and
+/// without the Prepend and SpacingMark rules.  Every invalid UTF-8
+/// sequence is a cluster of its own.
+pub struct Graphemes<'a> {
+    chars: std::iter::Peekable<Chars<'a>>,
+    off: Offset,
+} 
+
+fn char_len(c: &Result<char, Utf8Error>) -> Offset {
+    match *c {
+        Ok(c) => c.len_utf8() as Offset,
+        Err(ref e) => e.bytes().len() as Offset,
+    } 
+} 
+
+/// Number of UTF-16 code units of c, one for each byte of an invalid
+/// sequence.
+fn utf16_len(c: &Result<char, Utf8Error>) -> usize {
+    match *c {
+        Ok(c) => c.len_utf16(),
+        Err(ref e) => e.bytes().len(),
+    } 
+} 
+
+impl<'a> Iterator for Graphemes<'a> {
+    type Item = (Offset, Offset);
+
+    fn next(&mut self) -> Option<(Offset, Offset)> {
+        use GraphemeCat::*;
+        let first = self.chars.next()?;
+        let start = self.off;
+        self.off += char_len(&first);
+        let mut prev = match first {
+            Ok(c) => grapheme_cat(c),
+            Err(_) => return Some((start, self.off)),
+        };
+        // Number of regional indicators in a row
+        let mut regional = if prev == RegionalIndicator { 1 } else { 0 };
+        // Have we seen Pictographic Extend* (ZWJ)?
+        let mut pictographic = prev == Pictographic;
+        while let Some(&Ok(c)) = self.chars.peek() {
+            let next = grapheme_cat(c);
+            let join = match (prev, next) {
+                (Cr, Lf) => true,
+                (Cr, _) | (Lf, _) | (Control, _) => false,
+                (_, Cr) | (_, Lf) | (_, Control) => false,
+                (L, L) | (L, V) | (L, Lv) | (L, Lvt) => true,
+                (Lv, V) | (Lv, T) | (V, V) | (V, T) => true,
+                (Lvt, T) | (T, T) => true,
+                (_, Extend) | (_, Zwj) => true,
+                (Zwj, Pictographic) => pictographic,
+                (RegionalIndicator, RegionalIndicator) => regional % 2 == 1,
+                _ => false,
+            };
+            if !join {
+                break;
+            } 
+            let c = self.chars.next().unwrap();
+            self.off += char_len(&c);
+            match next {
+                RegionalIndicator => regional += 1,
+                Pictographic => pictographic = true,
+                Extend => (),
+                Zwj => (),
+                _ => pictographic = false,
+            } 
+            if prev == Zwj && next != Pictographic {
+                pictographic = false;
+            } 
+            prev = next;
+        } 
+        Some((start, self.off))
+    } 
+} 
+
+/// Iterator over the lines of a Text as (line number, span of offsets
+/// in the text), the newline not included.  Yields the same lines as
+/// Lines.
+pub struct LineRanges<'a> {
+    bytes: Bytes<'a>,
+    /// Offset of the next byte of bytes
+    off: Offset,
+    line: Offset,
+} 
+
+impl<'a> Iterator for LineRanges<'a> {
+    type Item = (Offset, Span);
+
+    fn next(&mut self) -> Option<(Offset, Span)> {
+        let start = self.off;
+        let mut end = None;
+        for b in self.bytes.by_ref() {
+            self.off += 1;
+            if b == b'\n' {
+                end = Some(self.off - 1);
+                break;
             } 
+        } 
+        let end = match end {
+            Some(end) => end,
+            None if self.off > start => self.off,
+            None => return None,
         };
-        self.len -= (off2 - off1) as usize;
-        self.link(left, right);
-        self.invariant()
+        self.line += 1;
+        Some((self.line - 1, Span::new(start, end)))
     } 
+} 
 
-    /// Append bytes at end.
-    pub fn append(&mut self, bytes: &[u8]) {
-        if bytes.len() == 0 {
-            return;
+/// Streaming reader over the contents of a Text.
+pub struct TextReader<'a> {
+    pieces: Pieces<'a>,
+    /// The not yet read part of the current piece
+    source: Source,
+    rest: Span,
+} 
+
+impl<'a> io::Read for TextReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        while n < buf.len() {
+            if self.rest.is_empty() {
+                match self.pieces.next() {
+                    None => break,
+                    Some((_, p)) => {
+                        let d = self.pieces.text.get_piece(p);
+                        self.source = d.source;
+                        self.rest = d.span;
+                    } 
+                } 
+            } 
+            let k = std::cmp::min(self.rest.len(), (buf.len() - n) as Offset) as usize;
+            let (chunk, rest) = match self.rest.split(k as Offset) {
+                Some(halves) => halves,
+                None => (self.rest, Span::empty()),
+            };
+            buf[n..n + k].copy_from_slice(self.pieces.text.span_bytes(self.source, chunk));
+            self.rest = rest;
+            n += k;
         } 
-        let off = self.len() as u32;
-        self.insert(off, bytes);
+        Ok(n)
+    } 
+} 
+
+/// Writer that appends everything written to the end of a Text.
+pub struct TextAppender<'t, 'a: 't>(&'t mut Text<'a>);
+
+impl<'t, 'a> io::Write for TextAppender<'t, 'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.append(buf);
+        Ok(buf.len())
+    } 
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    } 
+} 
+
+/// Collects bytes for a new Text.  Pushing is just extending a Vec, and
+/// build turns that Vec (without copying it) into a text of a single piece
+/// with no history.  Cheaper than appending to a Text bit by bit, which
+/// adds a piece and an undo step each time.
+#[derive(Debug, Clone, Default)]
+pub struct TextBuilder {
+    bytes: Vec<u8>,
+} 
+
+impl TextBuilder {
+    pub fn new() -> TextBuilder {
+        TextBuilder { bytes: Vec::new() }
+    } 
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    } 
+
+    pub fn build<'a>(self) -> Text<'a> {
+        Text::from(self.bytes)
+    } 
+} 
+
+/// An editing position in a Text that remembers the piece it is in.
+/// Moving the cursor walks the piece list from there, so moving a short
+/// distance is cheap.  When typing (inserting at the cursor over and over)
+/// no lookup is needed at all, each insert costs only the O(log n) update
+/// of the tree.
+pub struct Cursor<'t, 'a: 't> {
+    text: &'t mut Text<'a>,
+    off: Offset,
+    /// What find_piece(off) would return
+    start: Offset,
+    piece: Piece,
+} 
+
+impl<'t, 'a> Cursor<'t, 'a> {
+    pub fn offset(&self) -> Offset {
+        self.off
     } 
 
-    /// Insert bytes at offset.
-    pub fn insert(&mut self, off:u32, bytes: &[u8]) {
-        if bytes.len() == 0 {
+    /// Insert bytes at the cursor and move the cursor behind them.
+    pub fn insert(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
             return;
         } 
-        let (start, piece) = self.find_piece(off);
-        let (span, prev, next) = {
-            let d = self.get_piece(piece);
-            (d.span, d.prev, d.next)
-        };
-        if let Some((left_span, right_span)) = span.split(off - start) {
-            let left = self.add_piece(left_span);
-            let span = self.buffer.append(bytes);
-            let middle = self.add_piece(span);
-            let right = self.add_piece(right_span);
-            self.link(prev, left);
-            self.link(left, middle);
-            self.link(middle, right);
-            self.link(right, next);
-        } else {
-            // insert at beginning aka in front of the piece
-            assert_eq!(start, off);
-            let span = self.buffer.append(bytes);
-            let p = self.add_piece(span);
-            self.link(p, piece);
-            self.link(prev, p);
+        let span = self.text.buffer.append(bytes);
+        self.piece = self.text.insert_in_piece(self.off, self.start, self.piece, span);
+        self.off += bytes.len() as Offset;
+        self.start = self.off;
+    } 
+
+    /// Move the cursor to off.  Panics if off > the length of the text.
+    /// Costs O(number of pieces between the old and new position).
+    pub fn move_to(&mut self, off: Offset) {
+        assert!(off <= self.text.len(), "offset {} out of range", off);
+        while off < self.start {
+            self.piece = self.text.get_piece(self.piece).prev;
+            self.start -= self.text.get_piece(self.piece).span.len();
         } 
-        self.len = self.len + bytes.len();
-        self.invariant();
+        while self.piece != SENTINEL {
+            let len = self.text.get_piece(self.piece).span.len();
+            if off < self.start + len {
+                break;
+            } 
+            self.start += len;
+            self.piece = self.text.get_piece(self.piece).next;
+        } 
+        self.off = off;
+    } 
+} 
+
+/// A group of edits that is either kept (commit) or, when dropped
+/// without commit (say because of an early return or a panic), undone by
+/// restoring the snapshot taken by Text::begin.  The edits are made
+/// through the Transaction, which derefs to the text for reading and has
+/// the usual edit methods.  There is no mutable access to the text
+/// itself, so that nothing can replace it or throw away the history a
+/// rollback needs (see compact), and no undo (undoing past the start
+/// and editing would forget the version to go back to).  Rolled back edits can be redone, as
+/// with restore.
+pub struct Transaction<'t, 'a: 't> {
+    text: &'t mut Text<'a>,
+    snapshot: Snapshot,
+    committed: bool,
+} 
+
+impl<'t, 'a> Transaction<'t, 'a> {
+    /// Keep the edits.
+    pub fn commit(mut self) {
+        self.committed = true;
+    } 
+
+    /// Text::insert
+    pub fn insert(&mut self, off: Offset, bytes: &[u8]) {
+        self.text.insert(off, bytes)
+    } 
+
+    /// Text::try_insert
+    pub fn try_insert(&mut self, off: Offset, bytes: &[u8]) -> Result<(), OffsetError> {
+        self.text.try_insert(off, bytes)
+    } 
+
+    /// Text::insert_text
+    pub fn insert_text(&mut self, off: Offset, other: &Text) {
+        self.text.insert_text(off, other)
+    } 
+
+    /// Text::append
+    pub fn append(&mut self, bytes: &[u8]) {
+        self.text.append(bytes)
+    } 
+
+    /// Text::delete
+    pub fn delete(&mut self, off1: Offset, off2: Offset) {
+        self.text.delete(off1, off2)
+    } 
+
+    /// Text::try_delete
+    pub fn try_delete(&mut self, off1: Offset, off2: Offset) -> Result<(), OffsetError> {
+        self.text.try_delete(off1, off2)
+    } 
+
+    /// Text::delete_ranges
+    pub fn delete_ranges(&mut self, ranges: &[(Offset, Offset)]) {
+        self.text.delete_ranges(ranges)
+    } 
+
+    /// Text::replace
+    pub fn replace(&mut self, off1: Offset, off2: Offset, bytes: &[u8]) {
+        self.text.replace(off1, off2, bytes)
+    } 
+
+    /// Text::apply_edits
+    pub fn apply_edits(&mut self, edits: &[Edit]) -> Result<(), EditError> {
+        self.text.apply_edits(edits)
+    } 
+} 
+
+impl<'t, 'a> std::ops::Deref for Transaction<'t, 'a> {
+    type Target = Text<'a>;
+
+    fn deref(&self) -> &Text<'a> {
+        self.text
+    } 
+} 
+
+impl<'t, 'a> Drop for Transaction<'t, 'a> {
+    fn drop(&mut self) {
+        self.text.transactions.0 = self.text.transactions.0.saturating_sub(1);
+        if !self.committed {
+            let restored = self.text.restore(&self.snapshot);
+            debug_assert!(restored, "transaction could not be rolled back");
+        } 
+    } 
+} 
+
+/// A Text that can no longer be edited, made by Text::freeze.  Only the
+/// bytes and the live pieces are kept (no undo history, markers or
+/// on_edit callback), so it is Send and Sync and can be read from
+/// several threads through the Arc.
+#[derive(Debug, Clone)]
+pub struct FrozenText<'a> {
+    original: Cow<'a, [u8]>,
+    buffer: AppendOnlyBuffer,
+    /// The live pieces as (offset in the text, source, span), in order
+    pieces: Vec<(Offset, Source, Span)>,
+    len: Offset,
+} 
+
+impl<'a> FrozenText<'a> {
+    /// Length in bytes
+    pub fn len(&self) -> Offset {
+        self.len
+    } 
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    } 
+
+    fn piece_bytes(&self, &(_, source, span): &(Offset, Source, Span)) -> &[u8] {
+        match source {
+            Source::Original => &self.original[index(span.off1) .. index(span.off2)],
+            Source::Added => self.buffer.get(span),
+        } 
+    } 
+
+    /// The content as borrowed slices, one per piece.
+    pub fn chunks(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        self.pieces.iter().map(move |p| self.piece_bytes(p))
+    } 
+
+    /// Iterator over all bytes
+    pub fn bytes(&self) -> impl Iterator<Item = u8> + '_ {
+        self.chunks().flat_map(|c| c.iter().cloned())
     } 
 
     pub fn to_vec(&self) -> Vec<u8> {
+        self.chunks().collect::<Vec<_>>().concat()
+    } 
+
+    /// Copy of the bytes between off1 (inclusive) and off2 (exclusive).
+    /// Empty if off2 <= off1, panics if off2 > len (as Text::slice).
+    pub fn slice(&self, off1: Offset, off2: Offset) -> Vec<u8> {
         let mut v = Vec::new();
-        for (_, p) in self.pieces() {
-            v.push_all(self.buffer.get(self.get_piece(p).span))
+        if off2 <= off1 {
+            return v;
+        } 
+        assert!(off2 <= self.len());
+        let first = self.pieces.partition_point(|p| p.0 + p.2.len() <= off1);
+        for p in &self.pieces[first..] {
+            if p.0 >= off2 {
+                break;
+            } 
+            let bytes = self.piece_bytes(p);
+            let from = index(off1.saturating_sub(p.0));
+            let to = index(std::cmp::min(off2 - p.0, p.2.len()));
+            v.extend_from_slice(&bytes[from..to]);
         } 
         v
     } 
 
-    pub fn to_utf8_string(&self) -> Result<String, std::string::FromUtf8Error> {
-        String::from_utf8(self.to_vec())
+    /// Offset of the first occurrence of needle
+    pub fn find(&self, needle: &[u8]) -> Option<Offset> {
+        if needle.is_empty() {
+            return Some(0);
+        } 
+        let end = kmp_search(needle, self.bytes())?;
+        Some((end - needle.len()) as Offset)
+    } 
+} 
+
+/// Priority of a piece in the treap.  A hash of the index is as good as
+/// a random number and makes the shape of the tree reproducible.
+fn priority(Piece(p): Piece) -> u64 {
+    let mut x = p as u64;
+    x ^= x >> 30;
+    x = x.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x ^= x >> 27;
+    x = x.wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+} 
+
+/// Offsets are Offset, but we need usize to index into the buffers.
+/// While Offset is usize this is a no-op, it is here so that changing
+/// Offset stays a one line change.
+#[allow(clippy::unnecessary_cast)]
+fn index(off: Offset) -> usize {
+    off as usize
+} 
+
+/// The Offset of the end of n bytes behind the first len ones, None if
+/// it does not fit.  While Offset is usize that never happens for bytes
+/// in memory, but it is the one place that has to check if Offset
+/// becomes smaller.
+#[allow(clippy::useless_conversion)]
+fn end_offset(len: usize, n: usize) -> Option<Offset> {
+    len.checked_add(n).and_then(|end| Offset::try_from(end).ok())
+} 
+
+/// Write x in 8 bytes, little endian, as everything in Text::serialize
+fn write_u64<W: Write>(w: &mut W, x: u64) -> io::Result<()> {
+    w.write_all(&x.to_le_bytes())
+} 
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+} 
+
+/// Write bytes, preceded by their length
+fn write_framed<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+    write_u64(w, bytes.len() as u64)?;
+    w.write_all(bytes)
+} 
+
+/// Read what write_framed wrote.  Does not trust the length, so a broken
+/// length is an error rather than a huge allocation.
+fn read_framed<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let len = read_u64(r)?;
+    let mut bytes = Vec::new();
+    r.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated bytes"));
+    } 
+    Ok(bytes)
+} 
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+} 
+
+/// Number of bytes of haystack up to and including the end of the first
+/// occurrence of needle.  Knuth-Morris-Pratt, so that we never have to
+/// look at a byte twice.
+fn kmp_search<I: Iterator<Item = u8>>(needle: &[u8], haystack: I) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    } 
+    // fail[i] is the length of the longest proper prefix of needle[..i+1]
+    // that is also a suffix of it.
+    let mut fail = vec![0; needle.len()];
+    let mut k = 0;
+    for i in 1..needle.len() {
+        while k > 0 && needle[i] != needle[k] {
+            k = fail[k - 1];
+        } 
+        if needle[i] == needle[k] {
+            k += 1;
+        } 
+        fail[i] = k;
+    } 
+    let mut k = 0;
+    for (i, b) in haystack.enumerate() {
+        while k > 0 && b != needle[k] {
+            k = fail[k - 1];
+        } 
+        if b == needle[k] {
+            k += 1;
+        } 
+        if k == needle.len() {
+            return Some(i + 1);
+        } 
+    } 
+    None
+} 
+
+/// Myers' diff of a and b, as edits against a with offsets shifted by
+/// base.  Takes O((n + m) d) time and O(d^2) space, where d is the number
+/// of bytes inserted and deleted.
+fn diff_bytes(a: &[u8], b: &[u8], base: Offset) -> Vec<Edit> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = n + m;
+    // v[max + k] is the furthest x reached on diagonal k = x - y,
+    // trace[d] is v before step d, restricted to the diagonals -d..=d.
+    let mut v = vec![0isize; 2 * max as usize + 2];
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    let at = |k: isize| (max + k) as usize;
+    'search: for d in 0..=max {
+        trace.push(v[at(-d)..=at(d)].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && v[at(k - 1)] < v[at(k + 1)]) {
+                v[at(k + 1)]
+            } else {
+                v[at(k - 1)] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            } 
+            v[at(k)] = x;
+            if x >= n && y >= m {
+                break 'search;
+            } 
+        } 
+    } 
+    // Walk back from the end, collecting the deletes (Err) and inserts (Ok)
+    // as positions in a.
+    let mut ops = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for d in (1..trace.len() as isize).rev() {
+        let v = &trace[d as usize];
+        let get = |k: isize| v[(k + d) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && get(k - 1) < get(k + 1)) { k + 1 } else { k - 1 };
+        let prev_x = get(prev_k);
+        let prev_y = prev_x - prev_k;
+        if prev_k == k + 1 {
+            ops.push(Ok((prev_x, b[prev_y as usize])));
+        } else {
+            ops.push(Err(prev_x));
+        } 
+        x = prev_x;
+        y = prev_y;
+    } 
+    ops.reverse();
+    let mut edits = Vec::new();
+    let mut i = 0;
+    while i < ops.len() {
+        let start = match ops[i] { Ok((x, _)) | Err(x) => x };
+        let mut end = start;
+        let mut bytes = Vec::new();
+        while i < ops.len() {
+            match ops[i] {
+                Ok((x, b)) if x == end => bytes.push(b),
+                Err(x) if x == end => end += 1,
+                _ => break,
+            } 
+            i += 1;
+        } 
+        let off = base + start as Offset;
+        if !bytes.is_empty() {
+            edits.push(Edit::Insert { off, bytes });
+        } 
+        if end > start {
+            edits.push(Edit::Delete { off, len: (end - start) as Offset });
+        } 
     } 
+    edits
+} 
+
+fn count_newlines(bytes: &[u8]) -> Offset {
+    bytes.iter().filter(|&&b| b == b'\n').count() as Offset
 } 
 
-#[test]
-fn test_pieces() {
-    let t = Text::new();
-    assert_eq!(t.pieces().collect::<Vec<_>>(), vec![]);
-} 
+// The sentinel is always stored at position 0 in the pieces vector
+const SENTINEL: Piece = Piece(0);
+
+/// auto_compact does not bother with fewer pieces
+const AUTO_COMPACT_MIN_PIECES: usize = 64;
+
+/// First bytes of a serialized text, the last one is the format version
+const SERIALIZE_MAGIC: &[u8; 8] = b"piece\0\0\x02";
+
+impl<'a> Text<'a> {
+    pub fn new() -> Text<'a> {
+        Text::with_capacity(4096)
+    } 
+
+    /// An empty text with room for cap inserted bytes, to avoid
+    /// reallocating while loading something big.
+    pub fn with_capacity(cap: usize) -> Text<'a> {
+        Text {
+            original: Cow::Borrowed(&[]),
+            buffer: AppendOnlyBuffer::with_capacity(cap),
+            pieces: vec![PieceData { 
+                source: Source::Added,
+                span: Span::empty(),
+                newlines: 0,
+                prev: SENTINEL,
+                next: SENTINEL,
+                left: SENTINEL,
+                right: SENTINEL,
+                weight: 0,
+            }],
+            root: SENTINEL,
+            len: 0,
+            pending: Vec::new(),
+            undo: Vec::new(),
+            redo: Vec::new(),
+            markers: Vec::new(),
+            version: 0,
+            last_version: 0,
+            interned: HashMap::new(),
+            on_edit: OnEdit(None),
+            compact_policy: CompactPolicy::Off,
+            compact_check_at: 0,
+            edit_count: 0,
+            transactions: OpenTransactions::default(),
+        } 
+    } 
+
+    /// A text whose content is original, as a single piece.
+    fn from_original(original: Cow<'a, [u8]>) -> Text<'a> {
+        let mut t = Text::new();
+        t.original = original;
+        if !t.original.is_empty() {
+            let len = t.original.len() as Offset;
+            let piece = t.add_piece(Source::Original, Span::new(0, len));
+            t.link(SENTINEL, piece);
+            t.link(piece, SENTINEL);
+            t.pending.clear();
+            t.root = piece;
+            t.len = len;
+        } 
+        t.invariant();
+        t
+    } 
+
+    /// A text whose content is original (say a memory mapped file),
+    /// without copying it.  Only inserted bytes will be copied.
+    pub fn from_mmap(original: &'a [u8]) -> Text<'a> {
+        Text::from_original(Cow::Borrowed(original))
+    } 
+
+    /// A text containing everything read from r, as a single piece.
+    pub fn from_reader<R: Read>(mut r: R) -> io::Result<Text<'a>> {
+        let mut bytes = Vec::new();
+        r.read_to_end(&mut bytes)?;
+        Ok(Text::from(bytes))
+    } 
+
+    /// A text containing the contents of the file at path p
+    pub fn load<P: AsRef<Path>>(p: P) -> io::Result<Text<'a>> {
+        Text::from_reader(std::fs::File::open(p)?)
+    } 
+
+    /// The bytes of span in the buffer given by source
+    fn span_bytes(&self, source: Source, span: Span) -> &[u8] {
+        match source {
+            Source::Original => &self.original[index(span.off1) .. index(span.off2)],
+            Source::Added => self.buffer.get(span),
+        } 
+    } 
+
+    /// The byte at off in the buffer given by source
+    fn span_byte(&self, source: Source, off: Offset) -> u8 {
+        match source {
+            Source::Original => self.original[index(off)],
+            Source::Added => self.buffer.get_byte(off),
+        } 
+    } 
+
+    /// Check the consistency of the piece list and tree.  This is O(n),
+    /// so only done when debug assertions (or the strict-invariants
+    /// feature) are enabled.
+    fn invariant(&self) {
+        if !cfg!(any(debug_assertions, feature = "strict-invariants")) {
+            return;
+        } 
+        if let Err(e) = self.check_integrity() {
+            panic!("{}", e);
+        } 
+    } 
+
+    /// Verify the internal structure of the text: that both walks of
+    /// the piece list visit the same pieces and add up to len, that
+    /// every span lies within its buffer and that the tree index
+    /// matches the list.  This is O(n), meant for fuzzing and self
+    /// checks.
+    pub fn check_integrity(&self) -> Result<(), IntegrityError> {
+        let forward = self.check_list()?;
+        let mut in_order = Vec::new();
+        if self.check_tree(self.root, &mut in_order, forward.len())? != self.len() || in_order != forward {
+            return Err(IntegrityError::TreeOrder);
+        } 
+        Ok(())
+    } 
+
+    /// The list part of check_integrity, returning the pieces in order.
+    fn check_list(&self) -> Result<Vec<Piece>, IntegrityError> {
+        let forward = self.walk(true)?;
+        let backward = self.walk(false)?;
+        if !forward.iter().eq(backward.iter().rev()) {
+            return Err(IntegrityError::WalksDiffer);
+        } 
+        let mut walked = 0;
+        for &p in &forward {
+            let d = self.get_piece(p);
+            if d.span.is_empty() {
+                return Err(IntegrityError::EmptyPiece { piece: p.0 });
+            } 
+            let buffer_len = match d.source {
+                Source::Original => self.original.len(),
+                Source::Added => self.buffer.len(),
+            } as Offset;
+            if d.span.off1 > d.span.off2 || d.span.off2 > buffer_len {
+                return Err(IntegrityError::SpanOutOfBuffer { piece: p.0, span: d.span, buffer_len });
+            } 
+            let actual = count_newlines(self.span_bytes(d.source, d.span));
+            if d.newlines != actual {
+                return Err(IntegrityError::NewlineCount { piece: p.0, cached: d.newlines, actual });
+            } 
+            walked += d.span.len();
+        } 
+        if walked != self.len() {
+            return Err(IntegrityError::Length { walked, len: self.len() });
+        } 
+        Ok(forward)
+    } 
+
+    /// The pieces of the list walked forward or backward, checking that
+    /// the walk ends.
+    fn walk(&self, forward: bool) -> Result<Vec<Piece>, IntegrityError> {
+        let step = |p: Piece| {
+            let d = self.get_piece(p);
+            if forward { d.next } else { d.prev }
+        };
+        let mut out = Vec::new();
+        let mut p = step(SENTINEL);
+        while p != SENTINEL {
+            if out.len() >= self.pieces.len() || p.0 >= self.pieces.len() {
+                return Err(IntegrityError::Cycle { forward });
+            } 
+            out.push(p);
+            p = step(p);
+        } 
+        Ok(out)
+    } 
+
+    /// Check weights and heap order of the tree rooted at t and append
+    /// its pieces in order to out, giving up once out has more than
+    /// limit pieces.  Returns the weight of t.
+    fn check_tree(&self, t: Piece, out: &mut Vec<Piece>, limit: usize) -> Result<Offset, IntegrityError> {
+        if t == SENTINEL {
+            return Ok(0);
+        } 
+        if out.len() > limit || t.0 >= self.pieces.len() {
+            return Err(IntegrityError::TreeOrder);
+        } 
+        let d = self.get_piece(t);
+        for &child in [d.left, d.right].iter() {
+            if child != SENTINEL && priority(child) > priority(t) {
+                return Err(IntegrityError::Tree { piece: t.0 });
+            } 
+        } 
+        let l = self.check_tree(d.left, out, limit)?;
+        out.push(t);
+        let r = self.check_tree(d.right, out, limit)?;
+        if d.weight != l + d.span.len() + r {
+            return Err(IntegrityError::Tree { piece: t.0 });
+        } 
+        Ok(d.weight)
+    } 
+
+    /// Iterator over all pieces (but never the sentinel)
+    fn pieces(&self) -> Pieces<'_> {
+        let next = self.get_piece(SENTINEL).next;
+        self.pieces_from(0, next)
+    } 
+
+    /// Iterator over the pieces starting at piece, which starts at
+    /// offset off in the text.
+    fn pieces_from(&self, off: Offset, piece: Piece) -> Pieces<'_> {
+        Pieces {
+            text: self,
+            next: piece,
+            off,
+        } 
+    } 
+
+    /// Number of pieces making up the text (not counting the ones only
+    /// kept around for undo).  O(number of pieces).
+    pub fn piece_count(&self) -> usize {
+        self.pieces().count()
+    } 
+
+    /// Number of edits made so far: inserts, deletes and replaces (each
+    /// range of delete_ranges counts, as does each replacement of calls
+    /// like replace_all), bytes changed by set_byte and clear of a non
+    /// empty text.  Edits changing nothing are not counted.  Undo, redo
+    /// and restore do not change it, so it only goes up, say for
+    /// autosaving every so many edits.
+    pub fn edit_count(&self) -> u64 {
+        self.edit_count
+    } 
+
+    /// The pieces making up the text as (offset in the text, span).  The
+    /// span is into the original bytes or the append only buffer, see
+    /// segments for which.  Meant for debugging and statistics.
+    pub fn spans(&self) -> impl Iterator<Item = (Offset, Span)> + '_ {
+        self.pieces().map(move |(off, p)| (off, self.get_piece(p).span))
+    } 
+
+    /// Offset in the text of the first live piece whose span is exactly s,
+    /// None if no piece has it (any more).  Spans are compared without
+    /// their source and several pieces can share a span (e.g. interned
+    /// inserts), in both cases the first match wins.  O(number of pieces).
+    pub fn offset_of_span(&self, s: Span) -> Option<Offset> {
+        self.spans().find(|&(_, span)| span == s).map(|(off, _)| off)
+    } 
+
+    /// The pieces making up the text as (offset in the text, bytes), the
+    /// bytes borrowed from the buffers.  Like chunks with the offsets.
+    pub fn iter_segments(&self) -> impl Iterator<Item = (Offset, &[u8])> + '_ {
+        self.pieces().map(move |(off, p)| {
+            let d = self.get_piece(p);
+            (off, self.span_bytes(d.source, d.span))
+        })
+    } 
+
+    /// The pieces making up the text as (span of the text, source), that
+    /// is which bytes came from the original and which were inserted.
+    /// Neighbouring pieces can have the same source.
+    pub fn segments(&self) -> Segments<'_> {
+        Segments { pieces: self.pieces() }
+    } 
+
+    /// Start and end offset of the piece containing off, so the bytes
+    /// from off to the end are one slice.  None at or past the end.
+    pub fn locate(&self, off: Offset) -> Option<(Offset, Offset)> {
+        if off >= self.len() {
+            return None;
+        } 
+        let (start, piece) = self.find_piece(off);
+        Some((start, start + self.get_piece(piece).span.len()))
+    } 
+
+    /// Does a piece start at off (or is off the end)?  False past the end.
+    pub fn is_piece_boundary(&self, off: Offset) -> bool {
+        off <= self.len() && self.find_piece(off).0 == off
+    } 
+
+    /// Length of Text in bytes
+    pub fn len(&self) -> Offset {
+        self.len
+    } 
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    } 
+
+    /// The content as borrowed slices, one per piece.
+    pub fn chunks(&self) -> Chunks<'_> {
+        self.chunks_in(0, self.len())
+    } 
+
+    /// The bytes between off1 (inclusive) and off2 (exclusive) as borrowed
+    /// slices, the first and last one cut to the range.  off2 is clamped
+    /// to the length, an empty or reversed range has no chunks.
+    pub fn chunks_in(&self, off1: Offset, off2: Offset) -> Chunks<'_> {
+        let off2 = std::cmp::min(off2, self.len());
+        let pieces = if off1 < off2 {
+            let (start, piece) = self.find_piece(off1);
+            self.pieces_from(start, piece)
+        } else {
+            self.pieces_from(off2, SENTINEL)
+        };
+        Chunks { pieces, off1, off2 }
+    } 
+
+    /// The content in owned blocks of exactly block_size bytes, regardless
+    /// of piece boundaries.  Only the last block may be shorter.  Panics
+    /// if block_size is 0.
+    pub fn blocks(&self, block_size: usize) -> Blocks<'_> {
+        assert!(block_size > 0, "block_size must not be 0");
+        Blocks { chunks: self.chunks(), rest: &[], block_size }
+    } 
+
+    /// Iterator over all bytes
+    pub fn bytes(&self) -> Bytes<'_> {
+        self.bytes_from(0)
+    } 
+
+    /// Iterator over all bytes and their offsets
+    pub fn byte_indices(&self) -> ByteIndices<'_> {
+        ByteIndices { bytes: self.bytes(), off: 0 }
+    } 
+
+    /// Iterator over all bytes starting at offset off
+    fn bytes_from(&self, off: Offset) -> Bytes<'_> {
+        let off = std::cmp::min(off, self.len());
+        let (start, piece) = self.find_piece(off);
+        let mut pieces = self.pieces_from(start, piece);
+        let pd = pieces.next().map(|(_, p)| self.get_piece(p));
+        Bytes {
+            pieces,
+            pd,
+            off: off - start,
+            remaining: index(self.len() - off),
+        } 
+    } 
+
+    /// Iterator over all bytes, last byte first
+    pub fn bytes_rev(&self) -> BytesRev<'_> {
+        self.bytes_rev_from(self.len())
+    } 
+
+    /// Iterator over the bytes in front of offset off, last byte first
+    fn bytes_rev_from(&self, off: Offset) -> BytesRev<'_> {
+        let off = std::cmp::min(off, self.len());
+        let (start, piece) = if off == 0 { (0, SENTINEL) } else { self.find_piece(off - 1) };
+        BytesRev {
+            text: self,
+            piece,
+            off: off - start,
+            remaining: index(off),
+        } 
+    } 
+
+    /// Iterator over all chars, decoding the bytes as UTF-8
+    pub fn chars(&self) -> Chars<'_> {
+        Chars { bytes: self.bytes().peekable() }
+    } 
+
+    /// Iterator over all lines
+    pub fn lines(&self) -> Lines<'_> {
+        Lines { bytes: self.bytes() }
+    } 
+
+    /// Iterator over the byte ranges of the lines, see LineRanges.
+    pub fn line_ranges(&self) -> LineRanges<'_> {
+        LineRanges { bytes: self.bytes(), off: 0, line: 0 }
+    } 
+
+    /// Iterator over the grapheme clusters, see Graphemes.
+    pub fn graphemes(&self) -> Graphemes<'_> {
+        Graphemes { chars: self.chars().peekable(), off: 0 }
+    } 
+
+    /// Offset of the first occurrence of needle
+    pub fn find(&self, needle: &[u8]) -> Option<Offset> {
+        self.find_from(needle, 0)
+    } 
+
+    /// Offset of the first occurrence of needle at or after start
+    pub fn find_from(&self, needle: &[u8], start: Offset) -> Option<Offset> {
+        self.find_mapped(needle, start, |b| b)
+    } 
+
+    /// Offsets of all occurrences of needle, found lazily.  Matches do not
+    /// overlap: after a match the search goes on behind it, so "aa" is
+    /// found at 0 and 2 in "aaaa".  The empty needle is found at every
+    /// offset from 0 to len.
+    pub fn find_iter<'b>(&self, needle: &'b [u8]) -> FindIter<'_, 'b> {
+        FindIter { text: self, needle, start: 0 }
+    } 
+
+    /// Offset of the first occurrence of needle, where ASCII letters match
+    /// regardless of case (all other bytes have to be equal).
+    pub fn find_ignore_ascii_case(&self, needle: &[u8]) -> Option<Offset> {
+        self.find_mapped(needle, 0, |b| b.to_ascii_lowercase())
+    } 
+
+    /// Offset of the first occurrence of needle at or after start, comparing
+    /// bytes after passing them through map.
+    fn find_mapped<F: Fn(u8) -> u8>(&self, needle: &[u8], start: Offset, map: F) -> Option<Offset> {
+        if start > self.len() {
+            return None;
+        } 
+        if needle.is_empty() {
+            return Some(start);
+        } 
+        let needle = needle.iter().map(|&b| map(b)).collect::<Vec<_>>();
+        let end = kmp_search(&needle, self.bytes_from(start).map(map))?;
+        Some(start + (end - needle.len()) as Offset)
+    } 
+
+    /// Offset of the last occurrence of needle.  Searches backwards from
+    /// the end, so only the bytes behind the match are looked at.
+    pub fn rfind(&self, needle: &[u8]) -> Option<Offset> {
+        let needle = needle.iter().rev().cloned().collect::<Vec<_>>();
+        let end = kmp_search(&needle, self.bytes_rev())?;
+        Some(self.len() - end as Offset)
+    } 
+
+    /// Edits that turn self into other.  Offsets refer to self before any
+    /// of the edits, edits are in ascending order and do not overlap, and
+    /// a replacement is an Insert followed by a Delete at the same offset.
+    /// So applying them in order works if each offset is shifted by the
+    /// change in length made by the edits before it.  The common prefix and
+    /// suffix are skipped, the rest is compared with Myers' algorithm,
+    /// which finds a shortest edit script.
+    pub fn diff(&self, other: &Text) -> Vec<Edit> {
+        let a = self.to_vec();
+        let b = other.to_vec();
+        let prefix = a.iter().zip(b.iter()).take_while(|&(x, y)| x == y).count();
+        let suffix = a[prefix..].iter().rev().zip(b[prefix..].iter().rev())
+            .take_while(|&(x, y)| x == y).count();
+        diff_bytes(&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix], prefix as Offset)
+    } 
+
+    /// Apply edits in order, with offsets referring to the text before
+    /// any of them and in ascending order (as returned by diff).  Each
+    /// offset is moved by the change in length made by the earlier edits,
+    /// an insert inside an earlier delete goes where the delete was, and a
+    /// delete overlapping an earlier one only deletes the rest of its
+    /// range.  All edits are checked before the first one is made, so if
+    /// any does not fit the text or comes before the one in front of it,
+    /// the text stays as it is and the error says which.  Its offsets are
+    /// the ones of the edits (the end for a delete), its len the length
+    /// before the edits.  The edits are one undo step.
+    pub fn apply_edits(&mut self, edits: &[Edit]) -> Result<(), EditError> {
+        let before = self.len();
+        // (offset in the text at that point, bytes to delete) of each edit
+        let mut moved = Vec::with_capacity(edits.len());
+        // Bytes inserted and deleted so far, end of the bytes deleted so
+        // far (against the text before the edits) and the previous offset
+        let (mut added, mut removed, mut deleted_to, mut last): (Offset, Offset, Offset, Offset) = (0, 0, 0, 0);
+        for (i, e) in edits.iter().enumerate() {
+            let off = match *e {
+                Edit::Insert { off, .. } | Edit::Delete { off, .. } => off,
+            };
+            let fail = |error| Err(EditError { index: i, error });
+            if off < last {
+                return fail(OffsetError::Reversed { off1: last, off2: off });
+            } 
+            last = off;
+            let start = std::cmp::max(off, deleted_to);
+            // Offset of the edit at that point, once off is known to fit
+            // (removed <= deleted_to <= start <= before)
+            let at = |removed: Offset, added: Offset| start - removed + added;
+            match *e {
+                Edit::Insert { ref bytes, .. } => {
+                    if off > before {
+                        return fail(OffsetError::OutOfBounds { off, len: before });
+                    } 
+                    moved.push((at(removed, added), 0));
+                    // the text would not fit in an Offset
+                    added = match end_offset(index(before - removed + added), bytes.len()) {
+                        Some(_) => added + bytes.len() as Offset,
+                        None => return fail(OffsetError::OutOfBounds { off: Offset::MAX, len: before }),
+                    };
+                },
+                Edit::Delete { len: n, .. } => {
+                    let end = match off.checked_add(n) {
+                        Some(end) if end <= before => end,
+                        _ => return fail(OffsetError::OutOfBounds { off: off.saturating_add(n), len: before }),
+                    };
+                    let n = end.saturating_sub(start);
+                    moved.push((at(removed, added), n));
+                    removed += n;
+                    deleted_to = std::cmp::max(deleted_to, end);
+                },
+            } 
+        } 
+        self.auto_compact();
+        let n = self.undo.len();
+        for (e, (off, len)) in edits.iter().zip(moved) {
+            match *e {
+                Edit::Insert { ref bytes, .. } => self.insert_unchecked(off, bytes),
+                Edit::Delete { .. } => self.delete_unchecked(off, off + len),
+            } 
+        } 
+        self.join_changes(n);
+        self.invariant();
+        Ok(())
+    } 
+
+    /// The byte at offset or None if off >= self.len()
+    pub fn byte_at(&self, off: Offset) -> Option<u8> {
+        if off >= self.len() {
+            return None;
+        } 
+        let (start, piece) = self.find_piece(off);
+        let d = self.get_piece(piece);
+        Some(self.span_byte(d.source, d.span.off1 + (off - start)))
+    } 
+
+    /// The byte in front of off, None if off is 0 or past the end.
+    pub fn byte_before(&self, off: Offset) -> Option<u8> {
+        off.checked_sub(1).and_then(|o| self.byte_at(o))
+    } 
+
+    /// Is off at the start of a char (or the end of the text), that is not
+    /// in the middle of a UTF-8 sequence?  Like str::is_char_boundary only
+    /// the byte at off is looked at, so any offset in front of a byte that
+    /// is not a continuation byte counts, even if the text is not valid
+    /// UTF-8.  False for offsets past the end.
+    pub fn is_char_boundary(&self, off: Offset) -> bool {
+        if off == self.len() {
+            return true;
+        } 
+        match self.byte_at(off) {
+            Some(b) => !is_utf8_continuation(b),
+            None => false,
+        } 
+    } 
+
+    /// Offsets of the start of the char before off and of the one after
+    /// the char at off, that is the nearest char boundaries (see
+    /// is_char_boundary) left and right of off, for moving a cursor by one
+    /// char.  As no char is longer than four bytes, steps are never longer
+    /// than that, even through a run of stray continuation bytes.  None at
+    /// the start respectively the end of the text.  Panics if off > len.
+    pub fn char_indices_around(&self, off: Offset) -> (Option<Offset>, Option<Offset>) {
+        assert!(off <= self.len(), "offset {} out of range", off);
+        let continuation = |o| self.byte_at(o).is_some_and(is_utf8_continuation);
+        let prev = if off == 0 {
+            None
+        } else {
+            let mut p = off - 1;
+            while p > 0 && off - p < 4 && continuation(p) {
+                p -= 1;
+            } 
+            Some(p)
+        };
+        let next = if off == self.len() {
+            None
+        } else {
+            let mut n = off + 1;
+            while n - off < 4 && continuation(n) {
+                n += 1;
+            } 
+            Some(n)
+        };
+        (prev, next)
+    } 
+
+    /// Whether the content looks binary rather than text, judging by
+    /// the first 8 KiB, see is_probably_binary_with.
+    pub fn is_probably_binary(&self) -> bool {
+        self.is_probably_binary_with(8192, 0.3)
+    } 
+
+    /// Whether the first cap bytes contain a NUL byte or more than
+    /// max_ratio of them are control characters other than whitespace
+    /// (tab, newline, carriage return, form feed) and backspace.  Bytes
+    /// above 127 count as text, they might be UTF-8.
+    pub fn is_probably_binary_with(&self, cap: usize, max_ratio: f64) -> bool {
+        let mut seen = 0;
+        let mut control = 0;
+        for b in self.bytes().take(cap) {
+            match b {
+                0 => return true,
+                b'\t' | b'\n' | b'\r' | 0x0c | 0x08 => {} 
+                0x01..=0x1f | 0x7f => control += 1,
+                _ => {} 
+            } 
+            seen += 1;
+        } 
+        seen > 0 && control as f64 > max_ratio * seen as f64
+    } 
+
+    /// The next word boundary after off: skip the run of word bytes (or
+    /// of whitespace) starting at off and return the offset of the first
+    /// byte of the other kind, or len.  Only ASCII whitespace separates
+    /// words, see next_word_boundary_with for other classifications.
+    pub fn next_word_boundary(&self, off: Offset) -> Offset {
+        self.next_word_boundary_with(off, |b| b.is_ascii_whitespace())
+    } 
+
+    /// The previous word boundary before off, like next_word_boundary
+    /// but skipping the run ending at off.  0 at the start.
+    pub fn prev_word_boundary(&self, off: Offset) -> Offset {
+        self.prev_word_boundary_with(off, |b| b.is_ascii_whitespace())
+    } 
+
+    /// next_word_boundary, with class telling which bytes belong
+    /// together.  A boundary is where class changes, so class can have
+    /// more than two values (e.g. to separate punctuation).  off is
+    /// clamped to len.
+    pub fn next_word_boundary_with<C, F>(&self, off: Offset, mut class: F) -> Offset
+        where C: PartialEq, F: FnMut(u8) -> C {
+        let off = std::cmp::min(off, self.len());
+        let mut bytes = self.chunks_in(off, self.len()).flatten();
+        let first = match bytes.next() {
+            Some(&b) => class(b),
+            None => return off,
+        };
+        off + 1 + bytes.take_while(|&&b| class(b) == first).count() as Offset
+    } 
+
+    /// prev_word_boundary, with class as for next_word_boundary_with
+    pub fn prev_word_boundary_with<C, F>(&self, off: Offset, mut class: F) -> Offset
+        where C: PartialEq, F: FnMut(u8) -> C {
+        let mut off = std::cmp::min(off, self.len());
+        let last = match self.byte_before(off) {
+            Some(b) => class(b),
+            None => return 0,
+        };
+        off -= 1;
+        while let Some(b) = self.byte_before(off) {
+            if class(b) != last {
+                break;
+            } 
+            off -= 1;
+        } 
+        off
+    } 
+
+    /// The offset of the bracket matching the one at off, searching
+    /// forward from an opening and backward from a closing one of ()[]{}
+    /// and counting nested brackets of the same kind.  Only bytes are
+    /// looked at, brackets in strings or comments count too.  None if
+    /// there is no bracket at off or it is unbalanced.
+    pub fn match_bracket(&self, off: Offset) -> Option<Offset> {
+        let b = self.byte_at(off)?;
+        let (open, close, forward) = match b {
+            b'(' => (b'(', b')', true),
+            b'[' => (b'[', b']', true),
+            b'{' => (b'{', b'}', true),
+            b')' => (b')', b'(', false),
+            b']' => (b']', b'[', false),
+            b'}' => (b'}', b'{', false),
+            _ => return None,
+        };
+        let mut depth = 0usize;
+        let mut matches = |b: u8| {
+            if b == open {
+                depth += 1;
+            } else if b == close {
+                depth -= 1;
+            } 
+            depth == 0
+        };
+        if forward {
+            self.bytes_from(off).position(&mut matches).map(|i| off + i as Offset)
+        } else {
+            self.bytes_rev_from(off + 1).position(&mut matches).map(|i| off - i as Offset)
+        } 
+    } 
+
+    /// A reader over all bytes
+    pub fn reader(&self) -> TextReader<'_> {
+        TextReader {
+            pieces: self.pieces(),
+            source: Source::Added,
+            rest: Span::empty(),
+        } 
+    } 
+
+    /// A writer appending to the end of the text
+    pub fn appender(&mut self) -> TextAppender<'_, 'a> {
+        TextAppender(self)
+    } 
+
+    /// Turn the text into a FrozenText, giving up the undo history.  The
+    /// bytes are moved, not copied, and clones of the Arc are O(1).
+    pub fn freeze(self) -> Arc<FrozenText<'a>> {
+        let pieces = self.frozen_pieces();
+        Arc::new(FrozenText { original: self.original, buffer: self.buffer, pieces, len: self.len })
+    } 
+
+    /// A FrozenText of the current content, say for a search on another
+    /// thread, while self can go on being edited.  Copies the append only
+    /// buffer and the original if it is owned (a borrowed one is shared).
+    pub fn snapshot_frozen(&self) -> Arc<FrozenText<'a>> {
+        Arc::new(FrozenText {
+            original: self.original.clone(),
+            buffer: self.buffer.clone(),
+            pieces: self.frozen_pieces(),
+            len: self.len,
+        })
+    } 
+
+    fn frozen_pieces(&self) -> Vec<(Offset, Source, Span)> {
+        self.pieces().map(|(off, p)| {
+            let d = self.get_piece(p);
+            (off, d.source, d.span)
+        }).collect()
+    } 
+
+    /// Start a transaction, see Transaction.
+    pub fn begin(&mut self) -> Transaction<'_, 'a> {
+        let snapshot = self.snapshot();
+        self.transactions.0 += 1;
+        Transaction { text: self, snapshot, committed: false }
+    } 
+
+    /// A cursor at off.  Panics if off > self.len().
+    pub fn cursor_at(&mut self, off: Offset) -> Cursor<'_, 'a> {
+        let (start, piece) = self.find_piece(off);
+        Cursor { text: self, off, start, piece }
+    } 
+
+    fn get_piece(&self, Piece(p): Piece) -> &PieceData {
+        &self.pieces[p]
+    } 
+
+    fn get_piece_mut(&mut self, Piece(p): Piece) -> &mut PieceData {
+        &mut self.pieces[p]
+    } 
+
+    fn weight(&self, t: Piece) -> Offset {
+        self.get_piece(t).weight
+    } 
+
+    /// Recompute the weight of t from its children
+    fn update_weight(&mut self, t: Piece) {
+        let (left, right, len) = {
+            let d = self.get_piece(t);
+            (d.left, d.right, d.span.len())
+        };
+        let weight = self.weight(left) + len + self.weight(right);
+        self.get_piece_mut(t).weight = weight;
+    } 
+
+    /// Split the tree t into the pieces making up the first k bytes and
+    /// the rest.  k must be at a piece boundary.
+    fn split(&mut self, t: Piece, k: Offset) -> (Piece, Piece) {
+        if t == SENTINEL {
+            return (SENTINEL, SENTINEL);
+        } 
+        let (left, right, len) = {
+            let d = self.get_piece(t);
+            (d.left, d.right, d.span.len())
+        };
+        let lw = self.weight(left);
+        if k <= lw {
+            let (a, b) = self.split(left, k);
+            self.get_piece_mut(t).left = b;
+            self.update_weight(t);
+            (a, t)
+        } else {
+            assert!(k >= lw + len, "split inside of a piece");
+            let (a, b) = self.split(right, k - lw - len);
+            self.get_piece_mut(t).right = a;
+            self.update_weight(t);
+            (t, b)
+        } 
+    } 
+
+    /// The tree containing the pieces of a followed by those of b
+    fn merge(&mut self, a: Piece, b: Piece) -> Piece {
+        if a == SENTINEL {
+            b
+        } else if b == SENTINEL {
+            a
+        } else if priority(a) > priority(b) {
+            let right = self.get_piece(a).right;
+            let m = self.merge(right, b);
+            self.get_piece_mut(a).right = m;
+            self.update_weight(a);
+            a
+        } else {
+            let left = self.get_piece(b).left;
+            let m = self.merge(a, left);
+            self.get_piece_mut(b).left = m;
+            self.update_weight(b);
+            b
+        } 
+    } 
+
+    /// Replace the pieces between off1 and off2 (both at piece boundaries)
+    /// in the tree by the (not yet in the tree) pieces in new.
+    fn tree_replace(&mut self, off1: Offset, off2: Offset, new: &[Piece]) {
+        let root = self.root;
+        let (a, rest) = self.split(root, off1);
+        let (_, c) = self.split(rest, off2 - off1);
+        let mut m = SENTINEL;
+        for &p in new.iter() {
+            m = self.merge(m, p);
+        } 
+        let am = self.merge(a, m);
+        self.root = self.merge(am, c);
+    } 
+
+    /// Rebuild the tree from the piece list.  This is O(n), we do it
+    /// after operations that relink many pieces.
+    fn rebuild_tree(&mut self) {
+        let list = self.pieces().map(|(_, p)| p).collect::<Vec<_>>();
+        // Build the cartesian tree of the list in one pass, the stack
+        // is the right spine of the tree built so far.
+        let mut spine: Vec<Piece> = Vec::new();
+        for &p in list.iter() {
+            let mut left = SENTINEL;
+            while let Some(&top) = spine.last() {
+                if priority(top) >= priority(p) {
+                    break;
+                } 
+                left = top;
+                spine.pop();
+            } 
+            {
+                let d = self.get_piece_mut(p);
+                d.left = left;
+                d.right = SENTINEL;
+            } 
+            if let Some(&top) = spine.last() {
+                self.get_piece_mut(top).right = p;
+            } 
+            spine.push(p);
+        } 
+        self.root = spine.first().cloned().unwrap_or(SENTINEL);
+        let root = self.root;
+        self.rebuild_weights(root);
+    } 
+
+    fn rebuild_weights(&mut self, t: Piece) {
+        if t != SENTINEL {
+            let (left, right) = {
+                let d = self.get_piece(t);
+                (d.left, d.right)
+            };
+            self.rebuild_weights(left);
+            self.rebuild_weights(right);
+            self.update_weight(t);
+        } 
+    } 
+
+    fn link(&mut self, piece1: Piece, piece2: Piece) {
+        self.remember_links(piece1);
+        self.remember_links(piece2);
+        let Piece(p1) = piece1;
+        let Piece(p2) = piece2;
+        self.pieces[p1].next = piece2;
+        self.pieces[p2].prev = piece1;
+    } 
+
+    /// Remember the links of piece before the edit in progress changes
+    /// them for the first time.
+    fn remember_links(&mut self, piece: Piece) {
+        if self.pending.iter().any(|c| c.piece == piece) {
+            return;
+        } 
+        let links = {
+            let d = self.get_piece(piece);
+            (d.prev, d.next)
+        };
+        self.pending.push(LinkChange { piece, before: links, after: links });
+    } 
+
+    /// Finish the edit in progress by turning the remembered links into
+    /// an entry on the undo stack.  Any redo history is lost.
+    fn record_change(&mut self, off: Offset, removed: Offset, added: Span) {
+        let mut links = std::mem::take(&mut self.pending);
+        for c in links.iter_mut() {
+            let d = self.get_piece(c.piece);
+            c.after = (d.prev, d.next);
+        } 
+        if removed != 0 || !added.is_empty() {
+            self.edit_count += 1;
+        } 
+        let before = self.version;
+        self.last_version += 1;
+        self.version = self.last_version;
+        self.undo.push(Change { links, off, removed, added, before, after: self.version, joined: false });
+        self.redo.clear();
+    } 
+
+    /// Make the changes recorded since the undo stack had n entries a
+    /// single step for undo and redo.
+    fn join_changes(&mut self, n: usize) {
+        for c in self.undo.iter_mut().skip(n + 1) {
+            c.joined = true;
+        } 
+    } 
+
+    fn set_links(&mut self, Piece(p): Piece, (prev, next): (Piece, Piece)) {
+        let d = &mut self.pieces[p];
+        d.prev = prev;
+        d.next = next;
+    } 
+
+    /// Undo the last change on the undo stack, including structural ones.
+    fn undo_change(&mut self) -> bool {
+        match self.undo.pop() {
+            None => false,
+            Some(change) => {
+                for c in change.links.iter() {
+                    self.set_links(c.piece, c.before);
+                } 
+                self.len = self.len - change.inserted() + change.removed;
+                self.shift_markers(change.off, change.inserted(), change.removed);
+                self.notify_edit(change.off, change.inserted(), change.removed);
+                self.version = change.before;
+                self.redo.push(change);
+                true
+            } 
+        } 
+    } 
+
+    /// Redo the last change on the redo stack, including structural ones.
+    fn redo_change(&mut self) -> bool {
+        match self.redo.pop() {
+            None => false,
+            Some(change) => {
+                for c in change.links.iter() {
+                    self.set_links(c.piece, c.after);
+                } 
+                self.len = self.len - change.removed + change.inserted();
+                self.shift_markers(change.off, change.removed, change.inserted());
+                self.notify_edit(change.off, change.removed, change.inserted());
+                self.version = change.after;
+                self.undo.push(change);
+                true
+            } 
+        } 
+    } 
+
+    /// Undo the last edit.  Returns false if there was nothing to undo.
+    /// Structural changes (like coalesce) made after the edit are undone
+    /// along with it, as are the other edits of a call making several
+    /// (like delete_ranges).
+    pub fn undo(&mut self) -> bool {
+        if !self.undo.iter().any(|c| !c.is_structural()) {
+            return false;
+        } 
+        loop {
+            while self.undo.last().unwrap().is_structural() {
+                self.undo_change();
+            } 
+            let joined = self.undo.last().unwrap().joined;
+            self.undo_change();
+            if !joined {
+                break;
+            } 
+        } 
+        self.rebuild_tree();
+        self.invariant();
+        true
+    } 
+
+    /// Redo the last undone edit.  Returns false if there was nothing to redo.
+    /// Structural changes made after the edit are redone along with it.
+    pub fn redo(&mut self) -> bool {
+        if !self.redo.iter().any(|c| !c.is_structural()) {
+            return false;
+        } 
+        while self.redo.last().unwrap().is_structural() {
+            self.redo_change();
+        } 
+        self.redo_change();
+        while self.redo.last().is_some_and(|c| c.is_structural() || c.joined) {
+            self.redo_change();
+        } 
+        self.rebuild_tree();
+        self.invariant();
+        true
+    } 
+
+    /// Capture the current version of the text.  This is O(1) as the
+    /// append only buffer and the pieces already contain every version.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot { version: self.version }
+    } 
+
+    /// Go back (or forward) to the version captured by s.  Just
+    /// restoring the links of the sentinel and len is not enough, as
+    /// edits also relink the pieces around them, so we undo (or redo)
+    /// edits until we reach the version, which costs O(number of edits
+    /// in between).  Edits undone this way stay in the pieces vector as
+    /// orphans (they can be redone).  Returns false, leaving the text
+    /// alone, if the version is no longer reachable because new edits
+    /// were made after undoing past it.
+    pub fn restore(&mut self, s: &Snapshot) -> bool {
+        if self.undo.iter().any(|c| c.before == s.version) {
+            while self.version != s.version && self.undo_change() {} 
+        } else if self.redo.iter().any(|c| c.after == s.version) {
+            while self.version != s.version && self.redo_change() {} 
+        } 
+        self.rebuild_tree();
+        self.invariant();
+        self.version == s.version
+    } 
+
+    /// The edits made since s was taken, in the order they were made,
+    /// read off the undo history instead of comparing the texts (see
+    /// diff).  Unlike with diff each offset refers to the text after the
+    /// edits before it, so replaying them means applying them one by one,
+    /// a replace being a Delete followed by an Insert.  Inserted bytes are
+    /// read from the buffer, so they are what set_byte may have made of
+    /// them.  set_byte is not an edit in the history though: a byte it
+    /// changed since s that was in the text before is not reported.  None
+    /// if s is not in the past of the current version (say because it was
+    /// undone).
+    pub fn changes_since(&self, s: &Snapshot) -> Option<Vec<Edit>> {
+        let start = if s.version == self.version {
+            self.undo.len()
+        } else {
+            self.undo.iter().position(|c| c.before == s.version)?
+        };
+        let mut edits = Vec::new();
+        for c in &self.undo[start..] {
+            if c.removed > 0 {
+                edits.push(Edit::Delete { off: c.off, len: c.removed });
+            } 
+            if !c.added.is_empty() {
+                edits.push(Edit::Insert { off: c.off, bytes: self.buffer.get(c.added).to_vec() });
+            } 
+        } 
+        Some(edits)
+    } 
+
+    /// Add a marker at offset off.  Inserts before or at the marker move it
+    /// forward, deletes before it move it back, and a delete containing it
+    /// moves it to the start of the deleted range.  Replacing bytes by as
+    /// many other bytes (see overwrite) leaves markers where they are.
+    /// Undo and redo move markers as the inverse edit would, so a marker
+    /// moved by a delete does not go back to where it was.
+    pub fn add_marker(&mut self, off: Offset) -> Marker {
+        assert!(off <= self.len());
+        self.markers.push(off);
+        Marker(self.markers.len() - 1)
+    } 
+
+    /// Current offset of the marker
+    pub fn marker_offset(&self, Marker(m): Marker) -> Offset {
+        self.markers[m]
+    } 
+
+    /// Update the markers after removed bytes at off were replaced by
+    /// inserted bytes.
+    /// Call set_on_edit's callback for changing removed bytes at off into
+    /// inserted ones.
+    fn notify_edit(&mut self, off: Offset, removed: Offset, inserted: Offset) {
+        if let Some(ref mut f) = self.on_edit.0 {
+            f(&match (removed, inserted) {
+                (0, len) => EditEvent::Inserted { off, len },
+                (len, 0) => EditEvent::Deleted { off, len },
+                (removed, inserted) => EditEvent::Replaced { off, removed, inserted },
+            });
+        } 
+    } 
+
+    /// Call f after every change to the content (including undo and redo,
+    /// which apply the inverse of an edit), with the current len already
+    /// updated.  Replaces any earlier callback.  The callback has to be
+    /// Send so that Text stays Send.
+    pub fn set_on_edit(&mut self, f: EditCallback) {
+        self.on_edit = OnEdit(Some(f));
+    } 
+
+    fn shift_markers(&mut self, off: Offset, removed: Offset, inserted: Offset) {
+        if removed == inserted {
+            return;
+        } 
+        for m in self.markers.iter_mut() {
+            if *m >= off {
+                *m = off + inserted + m.saturating_sub(off + removed);
+            } 
+        } 
+    } 
+
+    /// Find the piece containing offset.  Return piece
+    /// and start position of piece in text.
+    /// Will return the sentinel iff off == self.len()
+    /// Returns the right piece if off between two
+    /// pieces
+    fn find_piece(&self, off:Offset) -> (Offset, Piece) {
+        assert!(off <= self.len(), "offset {} out of range", off);
+        if off == self.len() {
+            (off, SENTINEL)
+        } else { 
+            let mut start = 0;
+            let mut t = self.root;
+            loop {
+                let d = self.get_piece(t);
+                let lw = self.weight(d.left);
+                if off < start + lw {
+                    t = d.left;
+                } else if off < start + lw + d.span.len() {
+                    return (start + lw, t);
+                } else {
+                    start += lw + d.span.len();
+                    t = d.right;
+                } 
+            } 
+        } 
+    } 
+
+    fn add_piece(&mut self, source: Source, span: Span) -> Piece {
+        let newlines = count_newlines(self.span_bytes(source, span));
+        self.add_piece_with_newlines(source, span, newlines)
+    } 
+
+    /// add_piece, when we already know how many newlines the span has
+    fn add_piece_with_newlines(&mut self, source: Source, span: Span, newlines: Offset) -> Piece {
+        self.pieces.push(PieceData { 
+            source,
+            span, 
+            newlines,
+            prev: SENTINEL, 
+            next: SENTINEL,
+            left: SENTINEL,
+            right: SENTINEL,
+            weight: span.len(),
+        } );
+        Piece(self.pieces.len() - 1)
+    } 
+
+    /// Unlink the bytes between off1 (inclusive) and off2 (exclusive),
+    /// splitting the pieces at either end as necessary.  Returns the
+    /// pieces left and right of the hole, which the caller has to link
+    /// (possibly with new pieces in between).  Does not update len.
+    fn cut(&mut self, off1: Offset, off2: Offset) -> (Piece, Piece) {
+        let (lstart, lpiece) = self.find_piece(off1);
+        let (lsource, lspan) = {
+            let d = self.get_piece(lpiece);
+            (d.source, d.span)
+        };
+        let (rstart, rpiece) = self.find_piece(off2);
+        let (rsource, rspan) = {
+            let d = self.get_piece(rpiece);
+            (d.source, d.span)
+        };
+        let mut remainders = Vec::with_capacity(2);
+        // find_piece never returns a piece ending at the offset, so
+        // neither split can be at the end of the piece
+        let left = match lspan.split_checked(off1 - lstart) {
+            Ok((Some(left_span), Some(_))) => {
+                let l = self.get_piece(lpiece).prev;
+                let remainder = self.add_piece(lsource, left_span);
+                self.link(l, remainder);
+                remainders.push(remainder);
+                remainder
+            } 
+            // We are deleting all of piece
+            Ok((None, _)) => self.get_piece(lpiece).prev,
+            r => panic!("bad split of the piece at {} for {}: {:?}", lstart, off1, r),
+        };
+        let right = match rspan.split_checked(off2 - rstart) {
+            Ok((Some(_), Some(right_span))) => {
+                let r = self.get_piece(rpiece).next;
+                let remainder = self.add_piece(rsource, right_span);
+                self.link(remainder, r);
+                remainders.push(remainder);
+                remainder
+            } 
+            // We are at the beginning of piece and therefore
+            // won't delete anything of it
+            Ok((None, _)) => rpiece,
+            r => panic!("bad split of the piece at {} for {}: {:?}", rstart, off2, r),
+        };
+        let end = if right == rpiece { rstart } else { rstart + rspan.len() };
+        self.tree_replace(lstart, end, &remainders);
+        (left, right)
+    } 
+
+    /// Merge runs of neighbouring pieces whose spans are also neighbours
+    /// in the buffer into single pieces.  Returns the number of pieces
+    /// that went away.  The merge is recorded as a structural change, so
+    /// undo keeps working, but like any edit it forgets the redo history.
+    pub fn coalesce(&mut self) -> usize {
+        let mut merged = 0;
+        let mut p = self.get_piece(SENTINEL).next;
+        while p != SENTINEL {
+            let (prev, source, span) = {
+                let d = self.get_piece(p);
+                (d.prev, d.source, d.span)
+            };
+            let mut last = p;
+            let mut off2 = span.off2;
+            loop {
+                let next = self.get_piece(last).next;
+                if next == SENTINEL {
+                    break;
+                } 
+                let d = self.get_piece(next);
+                if d.source != source || d.span.off1 != off2 {
+                    break;
+                } 
+                off2 = self.get_piece(next).span.off2;
+                last = next;
+                merged += 1;
+            } 
+            let next = self.get_piece(last).next;
+            if last != p {
+                let piece = self.add_piece(source, Span::new(span.off1, off2));
+                self.link(prev, piece);
+                self.link(piece, next);
+            } 
+            p = next;
+        } 
+        if merged > 0 {
+            self.record_change(0, 0, Span::empty());
+            self.rebuild_tree();
+        } 
+        self.invariant();
+        merged
+    } 
+
+    /// Compact the text automatically according to policy, see
+    /// CompactPolicy.  Compacting throws away the history, so with Auto
+    /// undo and restoring snapshots may stop working at any edit.  Not
+    /// while a transaction is open though (see begin), so that it can
+    /// still be rolled back.
+    pub fn set_auto_compact(&mut self, policy: CompactPolicy) {
+        self.compact_policy = policy;
+        self.compact_check_at = 0;
+    } 
+
+    /// Called before an edit, compacts if the policy says so.  Computing
+    /// the stats is O(n log n), so we look again only once the pieces
+    /// vector has doubled in size.
+    fn auto_compact(&mut self) {
+        if self.compact_policy == CompactPolicy::Off || self.transactions.0 > 0
+            || self.pieces.len() < std::cmp::max(self.compact_check_at, AUTO_COMPACT_MIN_PIECES) {
+            return;
+        } 
+        let stats = self.stats();
+        if stats.total_pieces > 4 * stats.live_pieces || 2 * stats.dead_bytes > stats.buffer_used {
+            self.compact();
+        } 
+        self.compact_check_at = 2 * self.pieces.len();
+    } 
+
+    /// Copy the bytes still in use to a fresh append only buffer and
+    /// forget the pieces no longer in the text.  Returns the number of bytes
+    /// the buffer shrank by.  The original bytes are left alone (they may
+    /// well be borrowed).  This throws away the undo and redo history, so
+    /// afterwards undo does nothing and snapshots taken before fail to
+    /// restore.  Panics in a transaction, which could not be rolled
+    /// back any more.
+    pub fn compact(&mut self) -> Offset {
+        assert!(self.transactions.0 == 0, "compact would lose the history of an open transaction");
+        let live: Vec<(Source, Span)> = self.pieces()
+            .map(|(_, p)| (self.get_piece(p).source, self.get_piece(p).span))
+            .collect();
+        let old_len = self.buffer.len() as Offset;
+        // Pieces may share bytes (see insert_interned), so copy each run of
+        // bytes in use once and remember where it went.
+        let runs = self.used_runs();
+        let used = runs.iter().map(|span| index(span.len())).sum();
+        let mut buffer = AppendOnlyBuffer::from_vec(Vec::with_capacity(used));
+        let moved = runs.iter().map(|&span| buffer.append(self.buffer.get(span))).collect::<Vec<_>>();
+        self.pieces.truncate(1);
+        let mut prev = SENTINEL;
+        for (source, span) in live {
+            let span = match source {
+                Source::Added => {
+                    let i = runs.partition_point(|run| run.off2 < span.off2);
+                    let shift = runs[i].off1 - moved[i].off1;
+                    Span::new(span.off1 - shift, span.off2 - shift)
+                },
+                Source::Original => span,
+            };
+            let p = self.add_piece(source, span);
+            self.link(prev, p);
+            prev = p;
+        } 
+        self.link(prev, SENTINEL);
+        self.buffer = buffer;
+        self.pending.clear();
+        self.undo.clear();
+        self.redo.clear();
+        self.interned.clear();
+        self.rebuild_tree();
+        self.invariant();
+        old_len - self.buffer.len() as Offset
+    } 
+
+    /// The parts of the append only buffer used by the text, as
+    /// ascending, disjoint, not touching spans.
+    fn used_runs(&self) -> Vec<Span> {
+        let mut spans = self.pieces()
+            .map(|(_, p)| self.get_piece(p))
+            .filter(|d| d.source == Source::Added)
+            .map(|d| d.span)
+            .collect::<Vec<_>>();
+        spans.sort();
+        let mut runs: Vec<Span> = Vec::with_capacity(spans.len());
+        for span in spans {
+            match runs.last_mut().and_then(|last| last.try_merge(&span).map(|m| (last, m))) {
+                Some((last, merged)) => *last = merged,
+                None => runs.push(span),
+            } 
+        } 
+        runs
+    } 
+
+    /// How much memory the text uses and how much of it is wasted.
+    pub fn stats(&self) -> TextStats {
+        let live_pieces = self.piece_count();
+        let live_added: Offset = self.used_runs().iter().map(|span| span.len()).sum();
+        TextStats {
+            logical_len: self.len(),
+            buffer_capacity: self.buffer.capacity() as Offset,
+            buffer_used: self.buffer.len() as Offset,
+            live_pieces,
+            total_pieces: self.pieces.len(),
+            dead_bytes: self.buffer.len() as Offset - live_added,
+        } 
+    } 
+
+    /// Err unless off <= self.len()
+    fn check_offset(&self, off: Offset) -> Result<(), OffsetError> {
+        if off > self.len() {
+            Err(OffsetError::OutOfBounds { off, len: self.len() })
+        } else {
+            Ok(())
+        } 
+    } 
+
+    /// Delete bytes between off1 (inclusive) and off2 (exclusive).
+    /// Does nothing if off2 <= off1 (unlike try_delete), panics if off2
+    /// is past the end.
+    pub fn delete(&mut self, off1: Offset, off2: Offset) {
+        if off2 <= off1 {
+            return;
+        } 
+        self.try_delete(off1, off2).unwrap_or_else(|e| panic!("{}", e))
+    } 
+
+    /// Delete bytes between off1 (inclusive) and off2 (exclusive).
+    /// Leaves the text alone and returns an error if off2 < off1 or
+    /// off2 > self.len().
+    pub fn try_delete(&mut self, off1: Offset, off2: Offset) -> Result<(), OffsetError> {
+        if off2 < off1 {
+            return Err(OffsetError::Reversed { off1, off2 });
+        } 
+        self.check_offset(off2)?;
+        self.auto_compact();
+        self.delete_unchecked(off1, off2);
+        self.invariant();
+        Ok(())
+    } 
+
+    /// delete, for a range already known to be valid, without checking
+    /// the invariant.
+    fn delete_unchecked(&mut self, off1: Offset, off2: Offset) {
+        if off2 == off1 {
+            return;
+        } 
+        let (left, right) = self.cut(off1, off2);
+        self.len -= off2 - off1;
+        self.link(left, right);
+        self.shift_markers(off1, off2 - off1, 0);
+        self.notify_edit(off1, off2 - off1, 0);
+        self.record_change(off1, off2 - off1, Span::empty());
+    } 
+
+    /// Delete several ranges (off1, off2) at once, as for multiple cursors.
+    /// The ranges may come in any order but must not overlap (touching is
+    /// fine), offsets are those before any of the deletes.  They are
+    /// deleted last to first, all together one undo step.  Panics,
+    /// before deleting anything, if a range is invalid or two overlap.
+    pub fn delete_ranges(&mut self, ranges: &[(Offset, Offset)]) {
+        let mut ranges = ranges.to_vec();
+        for &(off1, off2) in &ranges {
+            if off2 < off1 {
+                panic!("{}", OffsetError::Reversed { off1, off2 });
+            } 
+            self.check_offset(off2).unwrap_or_else(|e| panic!("{}", e));
+        } 
+        ranges.sort_unstable_by(|a, b| b.cmp(a));
+        for w in ranges.windows(2) {
+            assert!(w[1].1 <= w[0].0, "ranges {}..{} and {}..{} overlap",
+                    w[1].0, w[1].1, w[0].0, w[0].1);
+        } 
+        self.auto_compact();
+        let n = self.undo.len();
+        for &(off1, off2) in &ranges {
+            self.delete_unchecked(off1, off2);
+        } 
+        self.join_changes(n);
+        self.invariant();
+    } 
+
+    /// Delete bytes between off1 (inclusive) and off2 (exclusive) and
+    /// return them.  Panics like delete if the range is invalid.
+    pub fn drain(&mut self, off1: Offset, off2: Offset) -> Vec<u8> {
+        let bytes = if off1 <= off2 && off2 <= self.len() {
+            self.slice(off1, off2)
+        } else {
+            Vec::new()
+        };
+        self.delete(off1, off2);
+        bytes
+    } 
+
+    /// Replace bytes between off1 (inclusive) and off2 (exclusive) by bytes.
+    /// Unlike a delete followed by an insert this only touches the piece
+    /// list once.  If off2 <= off1 this is just an insert at off1.
+    pub fn replace(&mut self, off1: Offset, off2: Offset, bytes: &[u8]) {
+        if off2 <= off1 && bytes.is_empty() {
+            return;
+        } 
+        self.auto_compact();
+        self.replace_unchecked(off1, off2, bytes);
+        self.invariant()
+    } 
+
+    /// replace, for a non empty change, without compacting or checking
+    /// the invariant.
+    fn replace_unchecked(&mut self, off1: Offset, off2: Offset, bytes: &[u8]) {
+        let off2 = std::cmp::max(off1, off2);
+        let (left, right) = self.cut(off1, off2);
+        let span = if bytes.is_empty() {
+            self.link(left, right);
+            Span::empty()
+        } else {
+            let span = self.buffer.append(bytes);
+            let middle = self.add_piece(Source::Added, span);
+            self.link(left, middle);
+            self.link(middle, right);
+            self.tree_replace(off1, off1, &[middle]);
+            span
+        };
+        self.len = self.len - (off2 - off1) + bytes.len() as Offset;
+        self.shift_markers(off1, off2 - off1, bytes.len() as Offset);
+        self.notify_edit(off1, off2 - off1, bytes.len() as Offset);
+        self.record_change(off1, off2 - off1, span);
+    } 
+
+    /// Make every line end (\r\n, or a \r or \n on its own) the given
+    /// one.  Returns the number of line ends changed.  Each change is a
+    /// replace, so markers move as they should, and all of them are one
+    /// undo step, but every change also adds up to three pieces.  Building
+    /// the normalized text in a fresh buffer would leave a single piece,
+    /// at the price of losing the markers and the history, so for texts
+    /// with many line ends to change it is best to follow this by compact
+    /// (or to normalize before making a Text).
+    pub fn normalize_newlines(&mut self, ending: LineEnding) -> usize {
+        let target = ending.as_bytes();
+        let mut changes = Vec::new();
+        let mut bytes = self.bytes().enumerate().peekable();
+        while let Some((off, b)) = bytes.next() {
+            let len = match b {
+                b'\r' if bytes.peek().map(|&(_, b)| b) == Some(b'\n') => {
+                    bytes.next();
+                    2
+                },
+                b'\r' | b'\n' => 1,
+                _ => continue,
+            };
+            let off = off as Offset;
+            if self.slice(off, off + len) != target {
+                changes.push((off, len));
+            } 
+        } 
+        self.auto_compact();
+        let n = self.undo.len();
+        for &(off, len) in changes.iter().rev() {
+            self.replace_unchecked(off, off + len, target);
+        } 
+        self.join_changes(n);
+        self.invariant();
+        changes.len()
+    } 
+
+    /// Do the first bytes equal prefix?  Stops at the first mismatch,
+    /// true for the empty prefix.
+    pub fn starts_with(&self, prefix: &[u8]) -> bool {
+        prefix.len() as Offset <= self.len() && self.bytes().take(prefix.len()).eq(prefix.iter().cloned())
+    } 
+
+    /// Do the last bytes equal suffix?  Compares last byte first and
+    /// stops at the first mismatch, true for the empty suffix.
+    pub fn ends_with(&self, suffix: &[u8]) -> bool {
+        suffix.len() as Offset <= self.len()
+            && self.bytes_rev().take(suffix.len()).eq(suffix.iter().rev().cloned())
+    } 
+
+    /// If the text starts with prefix delete it and return true, otherwise
+    /// leave the text alone and return false.
+    pub fn strip_prefix(&mut self, prefix: &[u8]) -> bool {
+        if !self.starts_with(prefix) {
+            return false;
+        } 
+        self.delete(0, prefix.len() as Offset);
+        true
+    } 
+
+    /// If the text ends with suffix delete it and return true, otherwise
+    /// leave the text alone and return false.  Only the last bytes are
+    /// looked at.
+    pub fn strip_suffix(&mut self, suffix: &[u8]) -> bool {
+        if !self.ends_with(suffix) {
+            return false;
+        } 
+        let n = suffix.len() as Offset;
+        let len = self.len();
+        self.delete(len - n, len);
+        true
+    } 
+
+    /// Delete every byte for which f is false, like Vec::retain.  f sees
+    /// each byte once, in order.  The runs of bytes to delete are collected
+    /// in that one pass and deleted by delete_ranges, so markers move as
+    /// they should and the whole call is one undo step.  No byte is copied,
+    /// but each run cut out of a piece adds one, compact afterwards if
+    /// that matters.
+    pub fn retain<F: FnMut(u8) -> bool>(&mut self, mut f: F) {
+        let mut runs: Vec<(Offset, Offset)> = Vec::new();
+        for (off, b) in self.byte_indices() {
+            if f(b) {
+                continue;
+            } 
+            match runs.last_mut() {
+                Some(run) if run.1 == off => run.1 += 1,
+                _ => runs.push((off, off + 1)),
+            } 
+        } 
+        self.delete_ranges(&runs);
+    } 
+
+    /// Replace every occurrence of needle by replacement, returning how
+    /// many were replaced.  Occurrences are found as by find_iter, so they
+    /// do not overlap, and replaced back to front, so the offsets of the
+    /// ones still to do stay valid.  As with normalize_newlines all
+    /// replacements together are one undo step.  The empty needle replaces
+    /// nothing.
+    pub fn replace_all(&mut self, needle: &[u8], replacement: &[u8]) -> usize {
+        if needle.is_empty() {
+            return 0;
+        } 
+        let found = self.find_iter(needle).collect::<Vec<_>>();
+        let len = needle.len() as Offset;
+        self.auto_compact();
+        let n = self.undo.len();
+        for &off in found.iter().rev() {
+            self.replace_unchecked(off, off + len, replacement);
+        } 
+        self.join_changes(n);
+        self.invariant();
+        found.len()
+    } 
+
+    /// Replace the bytes from off on by bytes, without changing the length
+    /// (so markers stay where they are).  Panics if off + bytes.len() is
+    /// past the end.  If the bytes are already there nothing happens: no
+    /// new pieces and no undo step.
+    pub fn overwrite(&mut self, off: Offset, bytes: &[u8]) {
+        let off2 = off + bytes.len() as Offset;
+        self.check_offset(off2).unwrap_or_else(|e| panic!("{}", e));
+        if self.chunks_in(off, off2).flatten().eq(bytes.iter()) {
+            return;
+        } 
+        self.replace(off, off2, bytes);
+    } 
+
+    /// Change the byte at off to b in place, without a new piece, if that
+    /// is safe, that is if the byte is an inserted one and no other piece
+    /// refers to it.  Other pieces include the ones only kept for undo
+    /// (a piece split by an edit lives on that way, as does the one in
+    /// front of an append, which makes way for a longer one) and bytes
+    /// remembered by insert_interned, changing a byte they share would
+    /// change them (and so older versions of the text) too.  Returns false,
+    /// leaving the text alone, if it is not safe (or off >= len), use
+    /// overwrite then.  So only bytes of the latest insert are sure to be
+    /// settable.  The change is not an undo step: undo and restore leave
+    /// the byte as it is (as long as the piece is in the text), and
+    /// changes_since does not see it.  Markers stay put.  Checking costs
+    /// O(number of pieces, including the ones kept for undo, + number of
+    /// interned strings).
+    pub fn set_byte(&mut self, off: Offset, b: u8) -> bool {
+        if off >= self.len() {
+            return false;
+        } 
+        let (start, piece) = self.find_piece(off);
+        let d = self.get_piece(piece);
+        if d.source != Source::Added {
+            return false;
+        } 
+        let at = d.span.off1 + (off - start);
+        let shared = self.pieces.iter().enumerate()
+            .any(|(i, e)| i != piece.0 && e.source == Source::Added && e.span.contains(at))
+            || self.interned.values().any(|s| s.contains(at));
+        if shared {
+            return false;
+        } 
+        let old = self.buffer.get_byte(at);
+        if old != b {
+            self.buffer.buf[index(at)] = b;
+            let d = self.get_piece_mut(piece);
+            if old == b'\n' {
+                d.newlines -= 1;
+            } else if b == b'\n' {
+                d.newlines += 1;
+            } 
+            self.edit_count += 1;
+            self.notify_edit(off, 1, 1);
+        } 
+        true
+    } 
+
+    /// Append bytes at end.
+    pub fn append(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        } 
+        let off = self.len();
+        self.insert(off, bytes);
+    } 
+
+    /// Make room for at least additional more inserted bytes.
+    pub fn reserve(&mut self, additional: usize) {
+        self.buffer.reserve(additional)
+    } 
+
+    /// Append bytes at end, like Vec::push (a synonym of append).
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.append(bytes)
+    } 
+
+    /// Append s at end.  As the end is always a char boundary this keeps
+    /// valid UTF-8 valid.
+    pub fn push_str(&mut self, s: &str) {
+        self.append(s.as_bytes())
+    } 
+
+    /// Insert bytes at offset.  Panics if off > self.len().  Inserting
+    /// right behind the bytes of the previous insert (as when typing)
+    /// does not add a piece, the one in front grows instead.
+    pub fn insert(&mut self, off:Offset, bytes: &[u8]) {
+        self.try_insert(off, bytes).unwrap_or_else(|e| panic!("{}", e))
+    } 
+
+    /// Like insert, but takes the bytes by value.  If nothing has been
+    /// inserted into the text before (the append only buffer is empty, as
+    /// in a new Text, whatever the original) the Vec becomes the buffer
+    /// and no byte is copied.  Otherwise the bytes are copied as by
+    /// insert.
+    pub fn insert_owned(&mut self, off: Offset, bytes: Vec<u8>) {
+        self.check_offset(off).unwrap_or_else(|e| panic!("{}", e));
+        if bytes.is_empty() {
+            return;
+        } 
+        self.auto_compact();
+        let (start, piece) = self.find_piece(off);
+        let span = self.buffer.append_owned(bytes);
+        self.insert_in_piece(off, start, piece, span);
+    } 
+
+    /// insert, at a Pos
+    pub fn insert_at(&mut self, Pos(off): Pos, bytes: &[u8]) {
+        self.insert(off, bytes)
+    } 
+
+    /// delete, between two Pos
+    pub fn delete_at(&mut self, Pos(off1): Pos, Pos(off2): Pos) {
+        self.delete(off1, off2)
+    } 
+
+    /// Insert bytes at offset.  Leaves the text alone and returns an
+    /// error if off > self.len().
+    pub fn try_insert(&mut self, off:Offset, bytes: &[u8]) -> Result<(), OffsetError> {
+        self.check_offset(off)?;
+        if bytes.is_empty() {
+            return Ok(());
+        } 
+        self.auto_compact();
+        self.insert_unchecked(off, bytes);
+        Ok(())
+    } 
+
+    /// insert, for an offset already known to be valid and without
+    /// compacting.
+    fn insert_unchecked(&mut self, off: Offset, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        } 
+        let (start, piece) = self.find_piece(off);
+        let span = self.buffer.append(bytes);
+        self.insert_in_piece(off, start, piece, span);
+    } 
+
+    /// Insert the (non empty) span of the append only buffer at off, where
+    /// (start, piece) is what find_piece(off) returns.  Returns the piece
+    /// that afterwards starts at off + span.len() (which is what find_piece
+    /// would return for it).
+    fn insert_in_piece(&mut self, off: Offset, start: Offset, piece: Piece, added: Span) -> Piece {
+        let (source, span, prev, next) = {
+            let d = self.get_piece(piece);
+            (d.source, d.span, d.prev, d.next)
+        };
+        let after = if let Some((left_span, right_span)) = span.split(off - start) {
+            let left = self.add_piece(source, left_span);
+            let middle = self.add_piece(Source::Added, added);
+            let right = self.add_piece(source, right_span);
+            self.link(prev, left);
+            self.link(left, middle);
+            self.link(middle, right);
+            self.link(right, next);
+            let end = start + left_span.len() + right_span.len();
+            self.tree_replace(start, end, &[left, middle, right]);
+            right
+        } else if prev != SENTINEL && self.get_piece(prev).source == Source::Added
+                  && self.get_piece(prev).span.off2 == added.off1 {
+            // The bytes follow the ones of the piece in front in the buffer
+            // too (always the case when typing), so replace that piece by
+            // one covering both.  A new piece rather than a longer span, as
+            // undo only restores links.
+            assert_eq!(start, off);
+            let d = self.get_piece(prev);
+            let (prev_span, prev_prev) = (d.span, d.prev);
+            let newlines = d.newlines + count_newlines(self.buffer.get(added));
+            let span = Span::new(prev_span.off1, added.off2);
+            let p = self.add_piece_with_newlines(Source::Added, span, newlines);
+            self.link(prev_prev, p);
+            self.link(p, piece);
+            self.tree_replace(off - prev_span.len(), off, &[p]);
+            piece
+        } else {
+            // insert at beginning aka in front of the piece
+            assert_eq!(start, off);
+            let p = self.add_piece(Source::Added, added);
+            self.link(p, piece);
+            self.link(prev, p);
+            self.tree_replace(off, off, &[p]);
+            piece
+        };
+        self.len += added.len();
+        self.shift_markers(off, 0, added.len());
+        self.notify_edit(off, 0, added.len());
+        self.record_change(off, 0, added);
+        self.invariant();
+        after
+    } 
+
+    /// Like insert, but bytes inserted this way before are not appended to
+    /// the buffer again, the new piece refers to the bytes appended the
+    /// first time.  Worth it when the same short strings are inserted over
+    /// and over.  The price is a hash lookup per insert and a copy of every
+    /// distinct string in a map (forgotten by compact and clear).  Plain
+    /// insert does not look at the map.
+    pub fn insert_interned(&mut self, off: Offset, bytes: &[u8]) {
+        self.check_offset(off).unwrap_or_else(|e| panic!("{}", e));
+        if bytes.is_empty() {
+            return;
+        } 
+        self.auto_compact();
+        let span = match self.interned.get(bytes) {
+            Some(&span) => span,
+            None => {
+                let span = self.buffer.append(bytes);
+                self.interned.insert(bytes.to_vec(), span);
+                span
+            },
+        };
+        let (start, piece) = self.find_piece(off);
+        self.insert_in_piece(off, start, piece, span);
+    } 
+
+    /// Insert the concatenation of chunks at off, without concatenating
+    /// them first: they are appended to the buffer one after the other and
+    /// become a single new piece.  Panics if off > self.len().
+    pub fn insert_all<'b, I: IntoIterator<Item = &'b [u8]>>(&mut self, off: Offset, chunks: I) {
+        self.check_offset(off).unwrap_or_else(|e| panic!("{}", e));
+        self.auto_compact();
+        let off1 = self.buffer.len() as Offset;
+        for chunk in chunks {
+            self.buffer.append(chunk);
+        } 
+        let span = Span::new(off1, self.buffer.len() as Offset);
+        if !span.is_empty() {
+            let (start, piece) = self.find_piece(off);
+            self.insert_in_piece(off, start, piece, span);
+        } 
+    } 
+
+    /// Insert the content of other at off.  Panics if off > self.len().
+    /// The bytes of other are copied straight into our buffer, piece by
+    /// piece, and become a single new piece.
+    pub fn insert_text(&mut self, off: Offset, other: &Text) {
+        self.insert_all(off, other.chunks())
+    } 
+
+    /// The bytes between off1 and off2 in the format of xxd, 16 bytes to
+    /// a row, see hexdump_with.
+    pub fn hexdump(&self, off1: Offset, off2: Offset) -> String {
+        self.hexdump_with(off1, off2, 16)
+    } 
+
+    /// The bytes between off1 and off2 in the format of xxd -c per_row:
+    /// one line per row of bytes with the offset (in the text) of the
+    /// row's first byte, the bytes in hex in groups of two and the bytes
+    /// again as ASCII, with a dot for everything not printable.  Ranges
+    /// as for slice, panics if per_row is 0.
+    pub fn hexdump_with(&self, off1: Offset, off2: Offset, per_row: usize) -> String {
+        use std::fmt::Write;
+        assert!(per_row > 0, "per_row must not be 0");
+        let mut out = String::new();
+        let bytes = self.slice(off1, off2);
+        for (i, row) in bytes.chunks(per_row).enumerate() {
+            write!(out, "{:08x}:", off1 + (i * per_row) as Offset).unwrap();
+            for j in 0..per_row {
+                if j % 2 == 0 {
+                    out.push(' ');
+                } 
+                match row.get(j) {
+                    Some(b) => write!(out, "{:02x}", b).unwrap(),
+                    None => out.push_str("  "),
+                } 
+            } 
+            out.push_str("  ");
+            out.extend(row.iter().map(|&b| if b == b' ' || b.is_ascii_graphic() { b as char } else { '.' }));
+            out.push('\n');
+        } 
+        out
+    } 
+
+    /// Copy of the bytes between off1 (inclusive) and off2 (exclusive).
+    /// Empty if off2 <= off1.
+    pub fn slice(&self, off1: Offset, off2: Offset) -> Vec<u8> {
+        let mut v = Vec::new();
+        if off2 <= off1 {
+            return v;
+        } 
+        assert!(off2 <= self.len());
+        for bytes in self.chunks_in(off1, off2) {
+            v.extend_from_slice(bytes);
+        } 
+        v
+    } 
+
+    /// Call f with the source and span of the pieces between off1 and off2
+    /// (off1 < off2 <= len), the first and last one cut to the range.
+    fn for_each_span<F: FnMut(Source, Span)>(&self, off1: Offset, off2: Offset, mut f: F) {
+        let (start, piece) = self.find_piece(off1);
+        for (s, p) in self.pieces_from(start, piece) {
+            if s >= off2 {
+                break;
+            } 
+            let d = self.get_piece(p);
+            let from = off1.saturating_sub(s);
+            let to = std::cmp::min(off2 - s, d.span.len());
+            f(d.source, Span::new(d.span.off1 + from, d.span.off1 + to));
+        } 
+    } 
+
+    /// Insert s at the byte offset off, unless that would split a char
+    /// (see is_char_boundary).  Together with delete_chars this keeps
+    /// valid UTF-8 valid.
+    pub fn insert_str(&mut self, off: Offset, s: &str) -> Result<(), NotCharBoundary> {
+        if !self.is_char_boundary(off) {
+            return Err(NotCharBoundary { off });
+        } 
+        self.insert(off, s.as_bytes());
+        Ok(())
+    } 
+
+    /// Byte offsets of the chars with indices c1 and c2 (c1 <= c2), as
+    /// counted by chars(), so every invalid UTF-8 sequence counts as one
+    /// char.  The number of chars is the index of the end.  None if there
+    /// are fewer than c2 chars.
+    fn offsets_of_chars(&self, c1: Offset, c2: Offset) -> Option<(Offset, Offset)> {
+        let mut chars = self.chars();
+        let mut off = 0;
+        let mut off1 = 0;
+        for c in 0..c2 {
+            if c == c1 {
+                off1 = off;
+            } 
+            off += char_len(&chars.next()?);
+        } 
+        Some((if c1 == c2 { off } else { off1 }, off))
+    } 
+
+    /// Length of the text in UTF-16 code units (as used by the language
+    /// server protocol): two for a char outside the basic multilingual
+    /// plane, one for any other char and one for each byte of an invalid
+    /// UTF-8 sequence, as if it had been decoded as Latin-1.
+    pub fn utf16_len(&self) -> usize {
+        self.chars().map(|c| utf16_len(&c)).sum()
+    } 
+
+    /// Number of UTF-16 code units (see utf16_len) in front of the byte
+    /// offset off.  An offset inside a char counts like the start of that
+    /// char, offsets past the end like len.
+    pub fn byte_to_utf16(&self, off: Offset) -> usize {
+        let mut units = 0;
+        let mut o = 0;
+        for c in self.chars() {
+            o += char_len(&c);
+            if o > off {
+                break;
+            } 
+            units += utf16_len(&c);
+        } 
+        units
+    } 
+
+    /// Byte offset of the UTF-16 code unit with index units (see
+    /// utf16_len), the inverse of byte_to_utf16.  An index inside a
+    /// surrogate pair gives the start of its char, indices past the end
+    /// len.
+    pub fn utf16_to_byte(&self, units: usize) -> Offset {
+        let mut seen = 0;
+        let mut off = 0;
+        for c in self.chars() {
+            seen += utf16_len(&c);
+            if seen > units {
+                break;
+            } 
+            off += char_len(&c);
+        } 
+        off
+    } 
+
+    /// Delete the chars with indices c1 (inclusive) to c2 (exclusive).
+    /// Panics if c2 < c1 or there are fewer than c2 chars.
+    pub fn delete_chars(&mut self, c1: Offset, c2: Offset) {
+        assert!(c1 <= c2, "char range {}..{} ends before it starts", c1, c2);
+        match self.offsets_of_chars(c1, c2) {
+            Some((off1, off2)) => self.delete(off1, off2),
+            None => panic!("char index {} is past the end of the text", c2),
+        } 
+    } 
+
+    /// Insert s in front of the char with index c (counted as by chars(),
+    /// so an invalid UTF-8 sequence counts as one char), or at the end for
+    /// c == the number of chars.  Past that it is an error, with the
+    /// number of chars as len, and the text is left alone.
+    pub fn insert_at_char(&mut self, c: Offset, s: &str) -> Result<(), OffsetError> {
+        match self.offsets_of_chars(c, c) {
+            Some((off, _)) => {
+                self.insert(off, s.as_bytes());
+                Ok(())
+            } 
+            None => Err(OffsetError::OutOfBounds { off: c, len: self.chars().count() as Offset }),
+        } 
+    } 
+
+    /// Make the text empty, keeping the memory of the buffer for reuse.
+    /// Unlike delete this forgets everything: the pieces vector goes back
+    /// to just the sentinel, the original bytes are dropped, the history is
+    /// thrown away (older snapshots no longer restore) and all markers move
+    /// to 0.
+    pub fn clear(&mut self) {
+        assert!(self.transactions.0 == 0, "clear would lose the history of an open transaction");
+        let len = self.len;
+        self.original = Cow::Borrowed(&[]);
+        self.buffer.clear();
+        self.interned.clear();
+        self.pieces.truncate(1);
+        self.link(SENTINEL, SENTINEL);
+        self.root = SENTINEL;
+        self.len = 0;
+        self.pending.clear();
+        self.undo.clear();
+        self.redo.clear();
+        for m in self.markers.iter_mut() {
+            *m = 0;
+        } 
+        if len > 0 {
+            self.edit_count += 1;
+            self.notify_edit(0, len, 0);
+        } 
+        self.last_version += 1;
+        self.version = self.last_version;
+        self.invariant();
+    } 
+
+    /// Delete everything from new_len on.  Does nothing if new_len >= len.
+    /// Finding the end is free (find_piece special cases it), so this is
+    /// just a delete.
+    pub fn truncate(&mut self, new_len: Offset) {
+        let len = self.len();
+        if new_len < len {
+            self.delete(new_len, len);
+        } 
+    } 
+
+    /// Append the content of other.  Its bytes are copied into our buffer
+    /// (as in insert_text) and other, including its history, is dropped.
+    pub fn append_text(&mut self, other: Text) {
+        let len = self.len();
+        self.insert_text(len, &other)
+    } 
+
+    /// Cut the text in two at off, like Vec::split_off: self keeps the
+    /// bytes before off (the rest is deleted, undoably) and the rest is
+    /// returned as a new text without history.  A borrowed original is
+    /// shared with the new text, all other bytes are copied into its
+    /// buffer.  Panics if off > self.len().
+    pub fn split_off(&mut self, off: Offset) -> Text<'a> {
+        self.check_offset(off).unwrap_or_else(|e| panic!("{}", e));
+        let mut rest = Text::new();
+        let borrowed = match self.original {
+            Cow::Borrowed(original) => Some(original),
+            Cow::Owned(_) => None,
+        };
+        if let Some(original) = borrowed {
+            rest.original = Cow::Borrowed(original);
+        } 
+        let len = self.len();
+        if off < len {
+            let mut prev = SENTINEL;
+            self.for_each_span(off, len, |source, span| {
+                let (source, span) = match (source, borrowed) {
+                    (Source::Original, Some(_)) => (source, span),
+                    _ => (Source::Added, rest.buffer.append(self.span_bytes(source, span))),
+                };
+                let p = rest.add_piece(source, span);
+                rest.link(prev, p);
+                prev = p;
+            });
+            rest.link(prev, SENTINEL);
+            rest.pending.clear();
+            rest.len = len - off;
+            rest.rebuild_tree();
+            rest.invariant();
+            self.delete(off, len);
+        } 
+        rest
+    } 
+
+    /// Number of times byte occurs between off1 (inclusive) and off2
+    /// (exclusive).  off2 is clamped to the length, and the count of an
+    /// empty or reversed range is 0.
+    pub fn count_in_range(&self, off1: Offset, off2: Offset, byte: u8) -> Offset {
+        self.chunks_in(off1, off2)
+            .map(|bytes| bytes.iter().filter(|&&b| b == byte).count() as Offset)
+            .sum()
+    } 
+
+    /// Number of chars between off1 and off2, or more precisely the number
+    /// of bytes that are not UTF-8 continuation bytes.  For valid UTF-8
+    /// starting and ending at char boundaries that is the same.  Ranges
+    /// are treated as in count_in_range.
+    pub fn char_count_in_range(&self, off1: Offset, off2: Offset) -> Offset {
+        self.chunks_in(off1, off2)
+            .map(|bytes| bytes.iter().filter(|&&b| !is_utf8_continuation(b)).count() as Offset)
+            .sum()
+    } 
+
+    /// Number of lines between off1 and off2, counted like lines() would
+    /// for just these bytes: a final newline does not start another line
+    /// and an empty range has no lines.  Ranges are treated as in
+    /// count_in_range.
+    pub fn line_count_in_range(&self, off1: Offset, off2: Offset) -> Offset {
+        let off2 = std::cmp::min(off2, self.len());
+        if off1 >= off2 {
+            return 0;
+        } 
+        let newlines = self.count_in_range(off1, off2, b'\n');
+        if self.byte_at(off2 - 1) == Some(b'\n') {
+            newlines
+        } else {
+            newlines + 1
+        } 
+    } 
+
+    /// Number of lines in the text, the number of newlines plus one.
+    /// This counts lines as line_of_offset numbers them: an empty text
+    /// has one (empty) line and a final newline starts another, empty
+    /// line, so "a\nb" and "a\n" both have 2 lines.  Note that this is
+    /// one more than line_count_in_range(0, len) when the text is empty
+    /// or ends in a newline.  O(pieces), the pieces cache their newlines.
+    pub fn line_count(&self) -> Offset {
+        self.pieces().map(|(_, p)| self.get_piece(p).newlines).sum::<Offset>() + 1
+    } 
+
+    /// The (0 based) line containing off, that is the number of
+    /// newlines before off.  Offsets past the end are in the last line.
+    pub fn line_of_offset(&self, off: Offset) -> Offset {
+        let mut line = 0;
+        for (s, p) in self.pieces() {
+            if s >= off {
+                break;
+            } 
+            let d = self.get_piece(p);
+            if off - s >= d.span.len() {
+                line += d.newlines;
+            } else {
+                let span = Span::new(d.span.off1, d.span.off1 + (off - s));
+                line += count_newlines(self.span_bytes(d.source, span));
+            } 
+        } 
+        line
+    } 
+
+    /// Offset of the first byte of the (0 based) line, or None if the
+    /// text has fewer lines.
+    pub fn offset_of_line(&self, line: Offset) -> Option<Offset> {
+        if line == 0 {
+            return Some(0);
+        } 
+        let mut seen = 0;
+        for (s, p) in self.pieces() {
+            let d = self.get_piece(p);
+            if seen + d.newlines < line {
+                seen += d.newlines;
+                continue;
+            } 
+            for (i, &b) in self.span_bytes(d.source, d.span).iter().enumerate() {
+                if b == b'\n' {
+                    seen += 1;
+                    if seen == line {
+                        return Some(s + i as Offset + 1);
+                    } 
+                } 
+            } 
+        } 
+        None
+    } 
+
+    /// The bytes of the (0 based) line n without its newline (a \r in
+    /// front of it stays), or None if n >= line_count.  So a final
+    /// newline is followed by an empty line.  Pieces in front of the line
+    /// are skipped using their newline count.
+    pub fn line(&self, n: Offset) -> Option<Vec<u8>> {
+        let start = self.offset_of_line(n)?;
+        let end = self.find_from(b"\n", start).unwrap_or(self.len());
+        Some(self.slice(start, end))
+    } 
+
+    /// Write all bytes to w, piece by piece, without copying them first.
+    /// Returns the number of bytes written, which is self.len().
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<Offset> {
+        let mut n = 0;
+        for (_, p) in self.pieces() {
+            let d = self.get_piece(p);
+            w.write_all(self.span_bytes(d.source, d.span))?;
+            n += d.span.len();
+        } 
+        Ok(n)
+    } 
+
+    /// Write everything about the text, including the piece layout, the
+    /// undo history and the markers, so that deserialize gives back the
+    /// same text (with an owned original).  The format is just numbers (8
+    /// bytes, little endian) and framed byte strings: a magic id, the
+    /// original and the append only buffer, the pieces as (source, off1,
+    /// off2, prev, next, left, right, weight), root and len, the undo
+    /// and redo stacks, the markers, the versions and what insert_interned
+    /// remembers.
+    pub fn serialize<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(SERIALIZE_MAGIC)?;
+        write_framed(w, &self.original)?;
+        write_framed(w, self.buffer.get(Span::new(0, self.buffer.len() as Offset)))?;
+        write_u64(w, self.pieces.len() as u64)?;
+        for d in &self.pieces {
+            write_u64(w, if d.source == Source::Original { 0 } else { 1 })?;
+            for &x in [d.span.off1, d.span.off2].iter() {
+                write_u64(w, x as u64)?;
+            } 
+            for &p in [d.prev, d.next, d.left, d.right].iter() {
+                write_u64(w, p.0 as u64)?;
+            } 
+            write_u64(w, d.weight as u64)?;
+        } 
+        write_u64(w, self.root.0 as u64)?;
+        write_u64(w, self.len as u64)?;
+        for changes in [&self.undo, &self.redo].iter() {
+            write_u64(w, changes.len() as u64)?;
+            for c in changes.iter() {
+                write_u64(w, c.links.len() as u64)?;
+                for l in &c.links {
+                    for &p in [l.piece, l.before.0, l.before.1, l.after.0, l.after.1].iter() {
+                        write_u64(w, p.0 as u64)?;
+                    } 
+                } 
+                for &x in [c.off, c.removed, c.added.off1, c.added.off2].iter() {
+                    write_u64(w, x as u64)?;
+                } 
+                write_u64(w, c.before)?;
+                write_u64(w, c.after)?;
+                write_u64(w, c.joined as u64)?;
+            } 
+        } 
+        write_u64(w, self.markers.len() as u64)?;
+        for &m in &self.markers {
+            write_u64(w, m as u64)?;
+        } 
+        write_u64(w, self.version)?;
+        write_u64(w, self.last_version)?;
+        write_u64(w, self.interned.len() as u64)?;
+        for (bytes, span) in &self.interned {
+            write_framed(w, bytes)?;
+            write_u64(w, span.off1 as u64)?;
+            write_u64(w, span.off2 as u64)?;
+        } 
+        Ok(())
+    } 
+
+    /// Do the versions of the undo and redo stacks lead to the current
+    /// one, each edit making a newer version, as restore relies on?
+    fn versions_chain(&self) -> bool {
+        let newer = self.undo.iter().chain(self.redo.iter())
+            .all(|c| c.before < c.after && c.after <= self.last_version);
+        let undo = self.undo.windows(2).all(|w| w[0].after == w[1].before)
+            && self.undo.last().is_none_or(|c| c.after == self.version);
+        let redo = self.redo.windows(2).all(|w| w[1].after == w[0].before)
+            && self.redo.last().is_none_or(|c| c.before == self.version);
+        newer && undo && redo && self.version <= self.last_version
+    } 
+
+    /// Does every version of the history have a proper piece list, as
+    /// undo and restore rely on?  Sets the links of each version in turn
+    /// and checks the list (but not the tree, which undo rebuilds), then
+    /// puts the current links back, going back through the undo stack
+    /// and then forward through the redo stack.  This is
+    /// O(changes * pieces).
+    fn check_history(&mut self) -> io::Result<()> {
+        let current: Vec<_> = self.pieces.iter().map(|d| (d.prev, d.next)).collect();
+        let len = self.len;
+        for undo in [true, false].iter().cloned() {
+            let changes = if undo { &self.undo } else { &self.redo };
+            for c in changes.iter().rev() {
+                for l in &c.links {
+                    let d = &mut self.pieces[l.piece.0];
+                    let (prev, next) = if undo { l.before } else { l.after };
+                    d.prev = prev;
+                    d.next = next;
+                } 
+                let (removed, inserted) = if undo { (c.inserted(), c.removed) } else { (c.removed, c.inserted()) };
+                self.len = match self.len.checked_sub(removed).and_then(|l| l.checked_add(inserted)) {
+                    Some(len) => len,
+                    None => return Err(invalid_data("history does not add up to a length")),
+                };
+                self.check_list().map_err(|e| invalid_data(&format!("history: {}", e)))?;
+            } 
+            for (d, &(prev, next)) in self.pieces.iter_mut().zip(current.iter()) {
+                d.prev = prev;
+                d.next = next;
+            } 
+            self.len = len;
+        } 
+        Ok(())
+    } 
+
+    /// Read a text written by serialize.  Everything read is checked
+    /// (spans against the buffers, pieces against the pieces vector, the
+    /// versions of the history against each other, the whole with
+    /// check_integrity and the list of every version with check_history),
+    /// anything wrong is an InvalidData error.
+    pub fn deserialize<R: Read>(r: &mut R) -> io::Result<Text<'a>> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != SERIALIZE_MAGIC {
+            return Err(invalid_data("not a serialized text"));
+        } 
+        let mut t = Text::new();
+        t.original = Cow::Owned(read_framed(r)?);
+        t.buffer = AppendOnlyBuffer::from_vec(read_framed(r)?);
+        let offset = |r: &mut R| -> io::Result<Offset> {
+            let x = read_u64(r)?;
+            Offset::try_from(x).map_err(|_| invalid_data("offset too large"))
+        };
+        let n = offset(r)?;
+        let piece = |r: &mut R| -> io::Result<Piece> {
+            let p = offset(r)?;
+            if p < n { Ok(Piece(index(p))) } else { Err(invalid_data("no such piece")) }
+        };
+        if n == 0 {
+            return Err(invalid_data("no sentinel"));
+        } 
+        t.pieces.clear();
+        for _ in 0..n {
+            let source = match read_u64(r)? {
+                0 => Source::Original,
+                1 => Source::Added,
+                _ => return Err(invalid_data("unknown source")),
+            };
+            let (off1, off2) = (offset(r)?, offset(r)?);
+            let buffer_len = match source {
+                Source::Original => t.original.len(),
+                Source::Added => t.buffer.len(),
+            } as Offset;
+            if off1 > off2 || off2 > buffer_len {
+                return Err(invalid_data("span out of its buffer"));
+            } 
+            let span = Span::new(off1, off2);
+            let newlines = count_newlines(t.span_bytes(source, span));
+            let (prev, next, left, right) = (piece(r)?, piece(r)?, piece(r)?, piece(r)?);
+            let weight = offset(r)?;
+            t.pieces.push(PieceData { source, span, newlines, prev, next, left, right, weight });
+        } 
+        t.root = piece(r)?;
+        t.len = offset(r)?;
+        for stack in 0..2 {
+            let changes = offset(r)?;
+            let mut v = Vec::new();
+            for _ in 0..changes {
+                let links = offset(r)?;
+                let mut c = Change {
+                    links: Vec::new(),
+                    off: 0, removed: 0, added: Span::empty(), before: 0, after: 0, joined: false,
+                };
+                for _ in 0..links {
+                    let p = piece(r)?;
+                    let before = (piece(r)?, piece(r)?);
+                    let after = (piece(r)?, piece(r)?);
+                    c.links.push(LinkChange { piece: p, before, after });
+                } 
+                c.off = offset(r)?;
+                c.removed = offset(r)?;
+                let (off1, off2) = (offset(r)?, offset(r)?);
+                if t.buffer.try_get(Span { off1, off2 }).is_none() {
+                    return Err(invalid_data("inserted bytes not in the buffer"));
+                } 
+                c.added = Span::new(off1, off2);
+                c.before = read_u64(r)?;
+                c.after = read_u64(r)?;
+                c.joined = match read_u64(r)? {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(invalid_data("joined is not a flag")),
+                };
+                v.push(c);
+            } 
+            if stack == 0 { t.undo = v } else { t.redo = v }
+        } 
+        let markers = offset(r)?;
+        for _ in 0..markers {
+            let m = offset(r)?;
+            if m > t.len {
+                return Err(invalid_data("marker past the end"));
+            } 
+            t.markers.push(m);
+        } 
+        t.version = read_u64(r)?;
+        t.last_version = read_u64(r)?;
+        if !t.versions_chain() {
+            return Err(invalid_data("versions of the history do not chain"));
+        } 
+        let interned = offset(r)?;
+        for _ in 0..interned {
+            let bytes = read_framed(r)?;
+            let span = (offset(r)?, offset(r)?);
+            if t.buffer.try_get(Span { off1: span.0, off2: span.1 }) != Some(&bytes[..]) {
+                return Err(invalid_data("interned bytes not in the buffer"));
+            } 
+            t.interned.insert(bytes, Span::new(span.0, span.1));
+        } 
+        t.check_integrity().map_err(|e| invalid_data(&e.to_string()))?;
+        t.check_history()?;
+        Ok(t)
+    } 
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        for (_, p) in self.pieces() {
+            let d = self.get_piece(p);
+            v.extend_from_slice(self.span_bytes(d.source, d.span))
+        } 
+        v
+    } 
+
+    /// The content, like to_vec, but without copying when the text is
+    /// a single piece covering all of an owned original (say a file loaded
+    /// with Text::load and not edited, or only edited and undone) or all of
+    /// the append only buffer.
+    pub fn into_bytes(self) -> Vec<u8> {
+        let first = self.get_piece(SENTINEL).next;
+        let d = self.get_piece(first);
+        let owned = match self.original {
+            Cow::Owned(_) => true,
+            Cow::Borrowed(_) => false,
+        };
+        if first == SENTINEL || d.next != SENTINEL {
+            self.to_vec()
+        } else if d.source == Source::Original && owned
+                  && d.span == Span::new(0, self.original.len() as Offset) {
+            self.original.into_owned()
+        } else if d.source == Source::Added
+                  && d.span == Span::new(0, self.buffer.len() as Offset) {
+            self.buffer.into_vec()
+        } else {
+            self.to_vec()
+        } 
+    } 
+
+    pub fn to_utf8_string(&self) -> Result<String, std::string::FromUtf8Error> {
+        String::from_utf8(self.to_vec())
+    } 
+
+    /// The content as a String, with invalid UTF-8 replaced by U+FFFD
+    /// like String::from_utf8_lossy does.  Same as to_string (see the
+    /// Display impl), decodes piece by piece without copying to a Vec
+    /// first.
+    pub fn to_string_lossy(&self) -> String {
+        let mut s = String::with_capacity(index(self.len()));
+        fmt::write(&mut s, format_args!("{}", self)).expect("writing to a String");
+        s
+    } 
+
+    /// One line per piece, `off1..off2 -> "bytes"`, unprintable bytes escaped.
+    /// Meant for debugging the piece table itself.
+    pub fn debug_pieces(&self) -> String {
+        let mut s = String::new();
+        for (off, p) in self.pieces() {
+            let d = self.get_piece(p);
+            s.push_str(&format!("{}..{} -> \"", off, off + d.span.len()));
+            for &b in self.span_bytes(d.source, d.span) {
+                s.extend(std::ascii::escape_default(b).map(char::from));
+            } 
+            s.push_str("\"\n");
+        } 
+        s
+    } 
+} 
+
+/// Write the valid UTF-8 in bytes to f, replacing invalid sequences
+/// by U+FFFD.  Returns the incomplete sequence at the end of bytes (if any)
+/// which may be completed by whatever follows.
+fn write_lossy<'b>(f: &mut fmt::Formatter, mut bytes: &'b [u8]) -> Result<&'b [u8], fmt::Error> {
+    loop {
+        match std::str::from_utf8(bytes) {
+            Ok(s) => {
+                f.write_str(s)?;
+                return Ok(&[]);
+            },
+            Err(e) => {
+                let valid = e.valid_up_to();
+                f.write_str(std::str::from_utf8(&bytes[..valid]).unwrap())?;
+                match e.error_len() {
+                    Some(n) => {
+                        f.write_str("\u{FFFD}")?;
+                        bytes = &bytes[valid + n..];
+                    },
+                    None => return Ok(&bytes[valid..]),
+                } 
+            },
+        } 
+    } 
+} 
+
+/// The content, with invalid UTF-8 replaced by U+FFFD the same way
+/// String::from_utf8_lossy does.  Written piece by piece.
+impl<'a> fmt::Display for Text<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // A character may be split between pieces. carry holds its start.
+        let mut carry: Vec<u8> = Vec::new();
+        for (_, p) in self.pieces() {
+            let d = self.get_piece(p);
+            let mut bytes = self.span_bytes(d.source, d.span);
+            if !carry.is_empty() {
+                // 3 more bytes are always enough to finish or reject
+                // the character started in carry.
+                let n = std::cmp::min(3, bytes.len());
+                let mut tmp = carry.clone();
+                tmp.extend_from_slice(&bytes[..n]);
+                let rest = write_lossy(f, &tmp)?;
+                if n == bytes.len() {
+                    carry = rest.to_vec();
+                    continue;
+                } 
+                bytes = &bytes[tmp.len() - rest.len() - carry.len()..];
+            } 
+            carry = write_lossy(f, bytes)?.to_vec();
+        } 
+        if !carry.is_empty() {
+            f.write_str("\u{FFFD}")?;
+        } 
+        Ok(())
+    } 
+} 
+
+impl<'a> Default for Text<'a> {
+    fn default() -> Text<'a> {
+        Text::new()
+    } 
+} 
+
+/// Texts are equal if they contain the same bytes, no matter how
+/// they are split into pieces.
+impl<'a, 'b> PartialEq<Text<'b>> for Text<'a> {
+    fn eq(&self, other: &Text<'b>) -> bool {
+        self.len() == other.len() && self.bytes().eq(other.bytes())
+    } 
+} 
+
+impl<'a> Eq for Text<'a> {} 
+
+/// Compares the content with bytes.
+impl<'a> PartialEq<[u8]> for Text<'a> {
+    fn eq(&self, other: &[u8]) -> bool {
+        self.len() == other.len() as Offset && self.bytes().eq(other.iter().cloned())
+    } 
+} 
+
+impl<'a, 'b> PartialEq<&'b [u8]> for Text<'a> {
+    fn eq(&self, other: &&'b [u8]) -> bool {
+        *self == **other
+    } 
+} 
+
+/// Compares the content with the UTF-8 bytes of a str.
+impl<'a> PartialEq<str> for Text<'a> {
+    fn eq(&self, other: &str) -> bool {
+        *self == *other.as_bytes()
+    } 
+} 
+
+impl<'a, 'b> PartialEq<&'b str> for Text<'a> {
+    fn eq(&self, other: &&'b str) -> bool {
+        *self == *other.as_bytes()
+    } 
+} 
+
+/// Hashes the content byte by byte, consistent with PartialEq.
+impl<'a> Hash for Text<'a> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.len() as u64);
+        for b in self.bytes() {
+            state.write_u8(b);
+        } 
+    } 
+} 
+
+/// a + &b appends the bytes of b to a (copying them, see insert_text),
+/// which it consumes.  The edit is an undo step of a's history.
+impl<'a, 'b, 'c> std::ops::Add<&'b Text<'c>> for Text<'a> {
+    type Output = Text<'a>;
+
+    fn add(mut self, other: &'b Text<'c>) -> Text<'a> {
+        self += other;
+        self
+    } 
+} 
+
+impl<'a, 'b, 'c> std::ops::AddAssign<&'b Text<'c>> for Text<'a> {
+    fn add_assign(&mut self, other: &'b Text<'c>) {
+        let len = self.len();
+        self.insert_text(len, other)
+    } 
+} 
+
+/// Takes over bytes as the original buffer, without copying them.
+impl<'a> From<Vec<u8>> for Text<'a> {
+    fn from(bytes: Vec<u8>) -> Text<'a> {
+        Text::from_original(Cow::Owned(bytes))
+    } 
+} 
+
+/// Copies bytes, use Text::from_mmap to borrow them instead.
+impl<'a, 'b> From<&'b [u8]> for Text<'a> {
+    fn from(bytes: &'b [u8]) -> Text<'a> {
+        Text::from(bytes.to_vec())
+    } 
+} 
+
+impl<'a, 'b> From<&'b str> for Text<'a> {
+    fn from(s: &'b str) -> Text<'a> {
+        Text::from(s.as_bytes())
+    } 
+} 
+
+#[test]
+fn test_pieces() {
+    let t = Text::new();
+    assert_eq!(t.pieces().collect::<Vec<_>>(), vec![]);
+} 
+
+#[cfg(test)]
+mod tests {
+    /// Tiny deterministic pseudo random number generator, good enough
+    /// to generate random edit sequences.
+    pub struct Rng(u64);
+
+    impl Rng {
+        pub fn new(seed: u64) -> Rng {
+            Rng(seed)
+        } 
+
+        /// A number in 0..n
+        pub fn below(&mut self, n: super::Offset) -> super::Offset {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            ((self.0 >> 33) % n as u64) as super::Offset
+        } 
+    } 
+
+    mod span {
+        use super::super::*;
+
+        #[test]
+        fn basics() {
+            let s = Span::new(1, 1);
+            assert_eq!(s.len(), 0);
+            assert!(s.is_empty());
+            let s2 = Span::new(3, 7);
+            assert!(s2.len() == 4);
+        } 
+
+        #[test]
+        fn split() {
+            let s = Span::new(3, 7);
+            assert_eq!(s.split(0), None);
+            assert_eq!(s.split(4), None);
+            assert_eq!(s.split(3), Some((Span { off1: 3, off2: 6 }, Span { off1: 6, off2: 7 })));
+        } 
+
+        #[test]
+        fn split_checked() {
+            let s = Span::new(3, 7);
+            assert_eq!(s.split_checked(0), Ok((None, Some(s))));
+            assert_eq!(s.split_checked(4), Ok((Some(s), None)));
+            assert_eq!(s.split_checked(1), Ok((Some(Span::new(3, 4)), Some(Span::new(4, 7)))));
+            assert_eq!(s.split_checked(5), Err(OffsetError::OutOfBounds { off: 5, len: 4 }));
+            let e = Span::new(2, 2);
+            assert_eq!(e.split_checked(0), Ok((None, Some(e))));
+            assert!(e.split_checked(1).is_err());
+        } 
+
+        #[test]
+        fn ordered() {
+            use std::collections::BTreeSet;
+            let spans = [Span::new(3, 9), Span::new(1, 4), Span::new(3, 5), Span::new(0, 10),
+                         Span::new(3, 5)];
+            let set = spans.iter().cloned().collect::<BTreeSet<_>>();
+            assert_eq!(set.into_iter().collect::<Vec<_>>(),
+                       vec![Span::new(0, 10), Span::new(1, 4), Span::new(3, 5), Span::new(3, 9)]);
+        } 
+
+        #[test]
+        fn contains() {
+            let s = Span::new(3, 7);
+            assert!(!s.contains(2));
+            assert!(s.contains(3));
+            assert!(s.contains(6));
+            assert!(!s.contains(7));
+            assert!(!Span::new(3, 3).contains(3));
+        } 
+
+        #[test]
+        fn intersect() {
+            let s = Span::new(3, 7);
+            // disjoint
+            assert_eq!(s.intersect(&Span::new(8, 9)), None);
+            // touching
+            assert_eq!(s.intersect(&Span::new(7, 9)), None);
+            assert_eq!(Span::new(0, 3).intersect(&s), None);
+            // overlapping
+            assert_eq!(s.intersect(&Span::new(5, 9)), Some(Span::new(5, 7)));
+            assert_eq!(Span::new(1, 4).intersect(&s), Some(Span::new(3, 4)));
+            // nested
+            assert_eq!(s.intersect(&Span::new(4, 5)), Some(Span::new(4, 5)));
+            assert_eq!(Span::new(4, 5).intersect(&s), Some(Span::new(4, 5)));
+        } 
+
+        #[test]
+        fn try_merge() {
+            let s = Span::new(3, 7);
+            // disjoint
+            assert_eq!(s.try_merge(&Span::new(8, 9)), None);
+            assert_eq!(Span::new(0, 2).try_merge(&s), None);
+            // touching
+            assert_eq!(s.try_merge(&Span::new(7, 9)), Some(Span::new(3, 9)));
+            assert_eq!(Span::new(0, 3).try_merge(&s), Some(Span::new(0, 7)));
+            // overlapping
+            assert_eq!(s.try_merge(&Span::new(5, 9)), Some(Span::new(3, 9)));
+            // nested
+            assert_eq!(s.try_merge(&Span::new(4, 5)), Some(s));
+            assert_eq!(Span::new(4, 5).try_merge(&s), Some(s));
+        } 
+    } 
+
+    mod append_only_buffer {
+        use super::super::*;
+
+        #[test] 
+        fn basics() {
+            let mut b = AppendOnlyBuffer::new();
+            let bytes = "Hello World".as_bytes();
+            let sp = b.append(bytes);
+            assert_eq!(b.get(sp), bytes);
+            let bytes2 = "Just testing".as_bytes();
+            let sp2 = b.append(bytes2);
+            assert_eq!(b.get(sp), bytes);
+            assert_eq!(b.get(sp2), bytes2);
+        } 
+
+        #[test]
+        fn try_get() {
+            let mut b = AppendOnlyBuffer::new();
+            let sp = b.append(b"Hello");
+            assert_eq!(b.try_get(sp), Some(&b"Hello"[..]));
+            assert_eq!(b.try_get(Span::new(1, 3)), Some(&b"el"[..]));
+            assert_eq!(b.try_get(Span::new(5, 5)), Some(&b""[..]));
+            assert_eq!(b.try_get(Span::new(3, 6)), None);
+            assert_eq!(b.try_get(Span::new(6, 6)), None);
+            assert_eq!(b.try_get_byte(4), Some(b'o'));
+            assert_eq!(b.try_get_byte(5), None);
+            assert_eq!(b.try_get_byte(Offset::MAX), None);
+        } 
+
+        #[test]
+        fn offset_range() {
+            assert_eq!(end_offset(3, 4), Some(7));
+            assert_eq!(end_offset(index(Offset::MAX) - 1, 1), Some(Offset::MAX));
+            assert_eq!(end_offset(usize::MAX, 1), None);
+            let mut b = AppendOnlyBuffer::new();
+            assert_eq!(b.try_append(b"abc"), Some(Span::new(0, 3)));
+            assert_eq!(b.try_append(b""), Some(Span::new(3, 3)));
+        } 
+
+        #[test]
+        fn capacity() {
+            let mut b = AppendOnlyBuffer::with_capacity(100000);
+            assert!(b.capacity() >= 100000);
+            b.append(b"Hello");
+            b.reserve(200000);
+            assert!(b.capacity() >= 200005);
+            let mut t = Text::with_capacity(10000);
+            assert!(t.stats().buffer_capacity >= 10000);
+            t.reserve(20000);
+            assert!(t.stats().buffer_capacity >= 20000);
+            t.push(b"abc");
+            assert_eq!(t.to_vec(), b"abc");
+        } 
+
+        #[test]
+        fn owned() {
+            let bytes = b"Hello World".to_vec();
+            let ptr = bytes.as_ptr();
+            let mut t = Text::new();
+            t.insert_owned(0, bytes);
+            assert_eq!(t.buffer.buf.as_ptr(), ptr);
+            assert_eq!(t.to_vec(), b"Hello World");
+            let bytes = b", dear".to_vec();
+            let ptr = bytes.as_ptr();
+            t.insert_owned(5, bytes);
+            assert_ne!(t.buffer.buf.as_ptr(), ptr);
+            assert_eq!(t.to_vec(), b"Hello, dear World");
+            t.insert_owned(0, Vec::new());
+            t.undo();
+            assert_eq!(t.to_vec(), b"Hello World");
+            let mut t = Text::from_mmap(b"World");
+            let bytes = b"Hello ".to_vec();
+            let ptr = bytes.as_ptr();
+            t.insert_owned(0, bytes);
+            assert_eq!(t.buffer.buf.as_ptr(), ptr);
+            assert_eq!(t.to_vec(), b"Hello World");
+        } 
+    } 
+
+    mod text_reader {
+        use super::super::*;
+        use std::io::Read;
+
+        #[test]
+        fn read_to_end() {
+            let mut t = Text::new();
+            t.insert(0, "World".as_bytes());
+            t.insert(0, "Hello ".as_bytes());
+            let mut v = Vec::new();
+            assert_eq!(t.reader().read_to_end(&mut v).unwrap(), 11);
+            assert_eq!(v, "Hello World".as_bytes());
+        } 
+
+        #[test]
+        fn small_buffer() {
+            let mut t = Text::new();
+            t.insert(0, "6789".as_bytes());
+            t.insert(0, "12345".as_bytes());
+            let mut r = t.reader();
+            let mut buf = [0; 3];
+            assert_eq!(r.read(&mut buf).unwrap(), 3);
+            assert_eq!(&buf, b"123");
+            // straddles the piece boundary
+            assert_eq!(r.read(&mut buf).unwrap(), 3);
+            assert_eq!(&buf, b"456");
+            assert_eq!(r.read(&mut buf).unwrap(), 3);
+            assert_eq!(&buf, b"789");
+            assert_eq!(r.read(&mut buf).unwrap(), 0);
+        } 
+
+        #[test]
+        fn empty() {
+            let t = Text::new();
+            let mut buf = [0; 3];
+            assert_eq!(t.reader().read(&mut buf).unwrap(), 0);
+        } 
+    } 
+
+    mod file_io {
+        use super::super::*;
+        use std::io::Write;
+
+        struct FailingReader;
+
+        impl Read for FailingReader {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("broken"))
+            } 
+        } 
+
+        #[test]
+        fn from_reader() {
+            let t = Text::from_reader("Hello World".as_bytes()).unwrap();
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello World");
+            assert_eq!(t.pieces().count(), 1);
+            let t = Text::from_reader(io::empty()).unwrap();
+            assert_eq!(t.len(), 0);
+            assert_eq!(t.pieces().count(), 0);
+            let e = Text::from_reader(FailingReader).unwrap_err();
+            assert_eq!(e.to_string(), "broken");
+        } 
+
+        /// Accepts one write, then fails
+        struct FailingWriter {
+            writes: usize,
+        } 
+
+        impl Write for FailingWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.writes += 1;
+                if self.writes > 1 {
+                    Err(io::Error::other("disk full"))
+                } else {
+                    Ok(buf.len())
+                } 
+            } 
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            } 
+        } 
+
+        #[test]
+        fn write_to() {
+            let mut t = Text::from("World");
+            t.insert(0, "Hello ".as_bytes());
+            t.append("!".as_bytes());
+            let mut v = Vec::new();
+            assert_eq!(t.write_to(&mut v).unwrap(), 12);
+            assert_eq!(v, "Hello World!".as_bytes());
+            let mut w = FailingWriter { writes: 0 };
+            assert_eq!(t.write_to(&mut w).unwrap_err().to_string(), "disk full");
+            assert_eq!(w.writes, 2);
+        } 
+
+        #[test]
+        fn load() {
+            let path = std::env::temp_dir().join(format!("piece-load-{}", std::process::id()));
+            std::fs::File::create(&path).unwrap().write_all("line 1\nline 2\n".as_bytes()).unwrap();
+            let t = Text::load(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(t.len(), 14);
+            assert_eq!(t.to_utf8_string().unwrap(), "line 1\nline 2\n");
+            assert!(Text::load(&path).is_err());
+        } 
+
+        fn assert_same(a: &Text, b: &Text) {
+            assert_eq!(a.to_vec(), b.to_vec());
+            assert_eq!(a.original, b.original);
+            assert_eq!(a.stats().buffer_used, b.stats().buffer_used);
+            assert_eq!(format!("{:?}", a.pieces), format!("{:?}", b.pieces));
+            assert_eq!(a.root, b.root);
+            assert_eq!(format!("{:?}", a.undo), format!("{:?}", b.undo));
+            assert_eq!(format!("{:?}", a.redo), format!("{:?}", b.redo));
+            assert_eq!(a.markers, b.markers);
+            assert_eq!((a.version, a.last_version), (b.version, b.last_version));
+            assert_eq!(a.interned, b.interned);
+        } 
+
+        #[test]
+        fn serialize() {
+            let mut t = Text::from_mmap(b"Hello\nWorld");
+            t.insert(5, b",");
+            t.insert_interned(0, b">> ");
+            t.insert_interned(9, b">> ");
+            t.delete(1, 2);
+            let m = t.add_marker(4);
+            t.append(b"!");
+            assert!(t.undo());
+            let mut v = Vec::new();
+            t.serialize(&mut v).unwrap();
+            let mut u = Text::deserialize(&mut &v[..]).unwrap();
+            assert_same(&t, &u);
+            assert!(t.redo() && u.redo());
+            assert!(t.undo() && u.undo() && t.undo() && u.undo());
+            assert_same(&t, &u);
+            assert_eq!(u.marker_offset(m), t.marker_offset(m));
+
+            let mut v = Vec::new();
+            Text::new().serialize(&mut v).unwrap();
+            assert_same(&Text::deserialize(&mut &v[..]).unwrap(), &Text::new());
+        } 
+
+        #[test]
+        fn deserialize_checks() {
+            let mut t = Text::from("Hello World");
+            t.insert(5, b",");
+            let mut v = Vec::new();
+            t.serialize(&mut v).unwrap();
+            let e = Text::deserialize(&mut &v[..v.len() - 1]).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+            let e = Text::deserialize(&mut &b"Hello World"[..]).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            // magic, original, buffer and the number of pieces, then the
+            // pieces of 8 numbers each
+            let pieces = 8 + (8 + 11) + (8 + 1) + 8;
+            let off2_of_first = pieces + 8 * 8 + 2 * 8;
+            let len = pieces + t.pieces.len() * 8 * 8 + 8;
+            for &at in [off2_of_first + 7, len].iter() {
+                let mut w = v.clone();
+                w[at] ^= 1;
+                let e = Text::deserialize(&mut &w[..]).unwrap_err();
+                assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            } 
+        } 
+
+        #[test]
+        fn deserialize_checks_versions() {
+            let mut t = Text::from("Hello World");
+            t.insert(5, b",");
+            t.delete(0, 1);
+            t.append(b"!");
+            assert!(t.undo());
+            let broken: [fn(&mut Text); 5] = [
+                |t| t.version += 1,
+                |t| t.last_version -= 1,
+                |t| t.undo[1].before = 7,
+                |t| t.undo[0].after = t.undo[0].before,
+                |t| t.redo[0].before -= 1,
+            ];
+            for f in broken.iter() {
+                let mut u = t.clone();
+                f(&mut u);
+                let mut v = Vec::new();
+                u.serialize(&mut v).unwrap();
+                let e = Text::deserialize(&mut &v[..]).unwrap_err();
+                assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            } 
+            let mut v = Vec::new();
+            t.serialize(&mut v).unwrap();
+            assert!(Text::deserialize(&mut &v[..]).is_ok());
+        } 
+
+        #[test]
+        fn deserialize_checks_history() {
+            let mut t = Text::from("Hello World");
+            t.insert(5, b",");
+            t.delete(0, 1);
+            t.append(b"!");
+            assert!(t.undo());
+            let broken: [fn(&mut Text); 4] = [
+                // a piece of an earlier version loops to itself
+                |t| for l in t.undo[0].links.iter_mut() { l.before.1 = l.piece },
+                |t| for l in t.redo[0].links.iter_mut() { l.after.1 = l.piece },
+                |t| t.undo[1].removed += 1,
+                |t| t.undo[0].removed = Offset::MAX,
+            ];
+            for f in broken.iter() {
+                let mut u = t.clone();
+                f(&mut u);
+                let mut v = Vec::new();
+                u.serialize(&mut v).unwrap();
+                let e = Text::deserialize(&mut &v[..]).unwrap_err();
+                assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            } 
+            let mut v = Vec::new();
+            t.serialize(&mut v).unwrap();
+            let mut u = Text::deserialize(&mut &v[..]).unwrap();
+            assert_same(&u, &t);
+            assert!(u.undo() && u.undo());
+            assert_eq!(u.to_utf8_string().unwrap(), "Hello World");
+        } 
+    } 
+
+    mod text_appender {
+        use super::super::*;
+        use std::io::Write;
+
+        #[test]
+        fn write() {
+            let mut t = Text::new();
+            t.insert(0, "lines:\n".as_bytes());
+            for n in 0..3 {
+                writeln!(t.appender(), "line {}", n).unwrap();
+            } 
+            t.appender().flush().unwrap();
+            assert_eq!(t.to_utf8_string().unwrap(), "lines:\nline 0\nline 1\nline 2\n");
+        } 
+    } 
+
+    mod text_builder {
+        use super::super::*;
+
+        #[test]
+        fn same_as_appends() {
+            let mut b = TextBuilder::new();
+            let mut t = Text::new();
+            for i in 0..1000 {
+                let line = format!("line {}\n", i);
+                b.push(line.as_bytes());
+                t.append(line.as_bytes());
+            } 
+            let built = b.build();
+            assert_eq!(built, t);
+            assert_eq!(built.piece_count(), 1);
+            assert!(TextBuilder::new().build().is_empty());
+        } 
+    } 
+
+    mod transaction {
+        use super::super::*;
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        #[test]
+        fn commit_and_rollback() {
+            let mut t = Text::from("Hello World");
+            {
+                let mut tr = t.begin();
+                tr.insert(5, b",");
+                tr.append(b"!");
+                tr.commit();
+            } 
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello, World!");
+            {
+                let mut tr = t.begin();
+                tr.delete(0, 7);
+                assert_eq!(tr.to_utf8_string().unwrap(), "World!");
+            } 
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello, World!");
+            // the rollback did not lose the earlier edits
+            assert!(t.undo());
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello, World");
+        } 
+
+        #[test]
+        fn panic_rolls_back() {
+            let mut t = Text::from("Hello World");
+            t.insert(0, b">> ");
+            let before = t.to_vec();
+            let r = catch_unwind(AssertUnwindSafe(|| {
+                let mut tr = t.begin();
+                tr.insert(8, b"big ");
+                tr.delete(0, 3);
+                tr.delete(100, 200);
+                tr.commit();
+            }));
+            assert!(r.is_err());
+            assert_eq!(t.to_vec(), before);
+            assert_eq!(t.check_integrity(), Ok(()));
+        } 
+
+        #[test]
+        fn edit_methods() {
+            let mut t = Text::from("abc");
+            t.insert(0, b"1");
+            {
+                let mut tr = t.begin();
+                tr.replace(0, 2, b"x");
+                assert!(tr.try_delete(2, 1).is_err());
+                assert!(tr.try_insert(9, b"y").is_err());
+                tr.insert_text(3, &Text::from("dd"));
+                tr.delete_ranges(&[(0, 1), (2, 3)]);
+                tr.apply_edits(&[Edit::Insert { off: 0, bytes: b"<".to_vec() }]).unwrap();
+                assert_eq!(tr.to_vec(), b"<bdd");
+            } 
+            assert_eq!(t.to_vec(), b"1abc");
+            assert_eq!(t.transactions.0, 0);
+            assert!(t.undo());
+            assert_eq!(t.to_vec(), b"abc");
+            t.compact();
+            t.clear();
+        } 
+    } 
+
+    mod cursor {
+        use super::super::*;
+        use super::Rng;
+
+        #[test]
+        fn typing() {
+            let mut t = Text::from("Hello World");
+            {
+                let mut c = t.cursor_at(5);
+                for &b in b", dear" {
+                    c.insert(&[b]);
+                } 
+                assert_eq!(c.offset(), 11);
+                c.move_to(17);
+                c.insert(b"!");
+                c.move_to(0);
+                c.insert(b">");
+            } 
+            assert_eq!(t.to_utf8_string().unwrap(), ">Hello, dear World!");
+            assert!(t.undo());
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello, dear World!");
+        } 
+
+        #[test]
+        fn same_as_insert() {
+            for seed in 0..20 {
+                let mut rng = Rng::new(seed);
+                let mut a = Text::from("original text");
+                let mut b = a.clone();
+                let mut moves = Vec::new();
+                {
+                    let mut c = a.cursor_at(0);
+                    for i in 0..50 {
+                        if rng.below(4) == 0 {
+                            let off = rng.below(13 + i + 1);
+                            let off = std::cmp::min(off, c.text.len());
+                            c.move_to(off);
+                        } 
+                        let bytes = vec![b'a' + (i % 26) as u8; index(rng.below(3)) + 1];
+                        moves.push((c.offset(), bytes.clone()));
+                        c.insert(&bytes);
+                    } 
+                } 
+                for (off, bytes) in moves {
+                    b.insert(off, &bytes);
+                } 
+                assert_eq!(a, b);
+            } 
+        } 
+    } 
+
+    mod into_bytes {
+        use super::super::*;
+
+        #[test]
+        fn without_copy() {
+            let v = b"Hello World".to_vec();
+            let ptr = v.as_ptr();
+            let t = Text::from(v);
+            let v = t.into_bytes();
+            assert_eq!(v.as_ptr(), ptr);
+            assert_eq!(v, b"Hello World");
+            let mut t = Text::new();
+            t.insert(0, b"Hello");
+            let ptr = t.buffer.get(Span::new(0, 5)).as_ptr();
+            let v = t.into_bytes();
+            assert_eq!(v.as_ptr(), ptr);
+            assert_eq!(v, b"Hello");
+        } 
+
+        #[test]
+        fn with_copy() {
+            let mut t = Text::from("Hello World");
+            t.insert(5, b",");
+            assert_eq!(t.into_bytes(), b"Hello, World");
+            let mut t = Text::from("Hello World");
+            t.delete(5, 11);
+            assert_eq!(t.into_bytes(), b"Hello");
+            let original = b"Hello".to_vec();
+            assert_eq!(Text::from_mmap(&original).into_bytes(), b"Hello");
+            assert!(Text::new().into_bytes().is_empty());
+        } 
+    } 
+
+    mod chunks {
+        use super::super::*;
+
+        #[test]
+        fn basics() {
+            let mut t = Text::from("Hello World");
+            t.insert(5, b",");
+            t.append(b"!");
+            assert_eq!(t.chunks().collect::<Vec<_>>(),
+                       vec![&b"Hello"[..], b",", b" World", b"!"]);
+            assert_eq!(t.chunks_in(3, 9).collect::<Vec<_>>(), vec![&b"lo"[..], b",", b" Wo"]);
+            assert_eq!(t.chunks_in(6, 7).collect::<Vec<_>>(), vec![&b" "[..]]);
+            assert_eq!(t.chunks_in(12, 100).collect::<Vec<_>>(), vec![&b"!"[..]]);
+            assert_eq!(t.chunks_in(5, 5).count(), 0);
+            assert_eq!(t.chunks_in(7, 3).count(), 0);
+            assert_eq!(Text::new().chunks().count(), 0);
+            assert_eq!(t.chunks().flatten().cloned().collect::<Vec<_>>(), t.to_vec());
+        } 
+
+        #[test]
+        fn blocks() {
+            let mut t = Text::from("Hello World");
+            t.insert(5, b",");
+            t.append(b"!");
+            for block_size in 1..16 {
+                let blocks = t.blocks(block_size).collect::<Vec<_>>();
+                assert_eq!(blocks.concat(), t.to_vec());
+                let (last, rest) = blocks.split_last().unwrap();
+                assert!(rest.iter().all(|b| b.len() == block_size));
+                assert!(!last.is_empty() && last.len() <= block_size);
+            } 
+            assert_eq!(t.blocks(4).collect::<Vec<_>>(),
+                       vec![b"Hell".to_vec(), b"o, W".to_vec(), b"orld".to_vec(), b"!".to_vec()]);
+            assert_eq!(Text::new().blocks(3).count(), 0);
+        } 
+    } 
+
+    mod on_edit {
+        use super::super::*;
+        use std::sync::{Arc, Mutex};
+
+        #[test]
+        fn events() {
+            let events = Arc::new(Mutex::new(Vec::new()));
+            let mut t = Text::from("Hello World");
+            let seen = events.clone();
+            t.set_on_edit(Box::new(move |e| seen.lock().unwrap().push(*e)));
+            t.insert(5, b",");
+            t.delete(0, 1);
+            t.replace(0, 4, b"J");
+            t.insert(3, b"");
+            t.delete(2, 2);
+            assert!(t.undo());
+            t.clone().insert(0, b"not seen");
+            t.clear();
+            assert_eq!(*events.lock().unwrap(),
+                       vec![EditEvent::Inserted { off: 5, len: 1 },
+                            EditEvent::Deleted { off: 0, len: 1 },
+                            EditEvent::Replaced { off: 0, removed: 4, inserted: 1 },
+                            EditEvent::Replaced { off: 0, removed: 1, inserted: 4 },
+                            EditEvent::Deleted { off: 0, len: 11 }]);
+        } 
+
+        #[test]
+        fn edit_count() {
+            let mut t = Text::from("Hello World");
+            assert_eq!(t.edit_count(), 0);
+            t.insert(5, b",");
+            t.delete(0, 1);
+            t.replace(0, 4, b"J");
+            t.insert(3, b"");
+            t.delete(2, 2);
+            assert_eq!(t.edit_count(), 3);
+            assert!(t.undo());
+            assert!(t.redo());
+            assert!(t.try_insert(100, b"x").is_err());
+            assert_eq!(t.edit_count(), 3);
+            t.delete_ranges(&[(0, 1), (2, 3)]);
+            assert_eq!(t.edit_count(), 5);
+            assert_eq!(t.clone().edit_count(), 5);
+            let mut t = Text::new();
+            t.append(b"ab");
+            assert!(t.set_byte(0, b'x'));
+            assert!(t.set_byte(0, b'x'));
+            assert_eq!(t.edit_count(), 2);
+            t.clear();
+            t.clear();
+            assert_eq!(t.edit_count(), 3);
+        } 
+    } 
+
+    mod pos {
+        use super::super::*;
+
+        #[test]
+        fn basics() {
+            let mut t = Text::from("World");
+            t.insert_at(Pos::start(), b"Hello ");
+            let end = Pos::end(&t);
+            assert_eq!(end, Pos(11));
+            t.insert_at(end, b"!");
+            t.delete_at(Pos::from(5), Pos(6));
+            assert_eq!(t.to_utf8_string().unwrap(), "HelloWorld!");
+            assert_eq!(Pos(3).saturating_sub(5), Pos::start());
+            assert_eq!(Pos(3).saturating_add(5), Pos(8));
+            assert_eq!(Pos(Offset::MAX).saturating_add(1), Pos(Offset::MAX));
+            assert_eq!(Offset::from(Pos(7)), 7);
+            let off: Offset = Pos(7).into();
+            assert_eq!(off, 7);
+            assert!(Pos(1) < Pos(2));
+        } 
+    } 
+
+    mod frozen {
+        use super::super::*;
+
+        fn pieces() -> Text<'static> {
+            let mut t = Text::from_mmap(b"Hello World");
+            t.insert(5, b",");
+            t.append(b"! Hello");
+            t.delete(0, 1);
+            t
+        } 
+
+        #[test]
+        fn reads() {
+            let t = pieces();
+            let content = t.to_vec();
+            let f = t.freeze();
+            let g = f.clone();
+            assert_eq!(f.to_vec(), b"ello, World! Hello");
+            assert_eq!(g.to_vec(), content);
+            assert_eq!(f.len(), 18);
+            assert!(!f.is_empty());
+            assert_eq!(f.bytes().collect::<Vec<_>>(), content);
+            assert_eq!(f.chunks().count(), 4);
+            for i in 0..=f.len() {
+                for j in i..=f.len() {
+                    assert_eq!(f.slice(i, j), &content[i..j]);
+                } 
+            } 
+            assert_eq!(f.slice(3, 1), b"");
+            assert_eq!(f.find(b"o, W"), Some(3));
+            assert_eq!(f.find(b"Hello"), Some(13));
+            assert_eq!(f.find(b"hello"), None);
+            assert_eq!(f.find(b""), Some(0));
+            assert!(Text::new().freeze().is_empty());
+        } 
+
+        #[test]
+        fn send_sync() {
+            fn shared<T: Send + Sync>(_: &T) {} 
+            let f = pieces().freeze();
+            shared(&f);
+            let g = f.clone();
+            let found = std::thread::spawn(move || g.find(b"World")).join().unwrap();
+            assert_eq!(found, Some(6));
+        } 
+
+        #[test]
+        fn snapshot_while_editing() {
+            let mut t = pieces();
+            let content = t.to_vec();
+            let f = t.snapshot_frozen();
+            let reader = {
+                let f = f.clone();
+                std::thread::spawn(move || {
+                    (0..100).map(|_| f.find(b"World").unwrap() + f.to_vec().len()).sum::<Offset>()
+                })
+            };
+            for i in 0..100 {
+                t.insert(0, b"World ");
+                if i % 2 == 0 {
+                    t.undo();
+                } 
+            } 
+            assert_eq!(reader.join().unwrap(), 100 * (6 + 18));
+            assert_eq!(f.to_vec(), content);
+            assert_eq!(t.len(), 18 + 50 * 6);
+            assert_eq!(t.snapshot_frozen().to_vec(), t.to_vec());
+        } 
+    } 
+
+    mod segments {
+        use super::super::*;
+
+        #[test]
+        fn basics() {
+            let mut t = Text::from_mmap(b"Hello World");
+            assert_eq!(t.segments().collect::<Vec<_>>(), vec![(Span::new(0, 11), Source::Original)]);
+            t.insert(5, b",");
+            t.append(b"!");
+            t.delete(0, 1);
+            assert_eq!(t.segments().collect::<Vec<_>>(),
+                       vec![(Span::new(0, 4), Source::Original),
+                            (Span::new(4, 5), Source::Added),
+                            (Span::new(5, 11), Source::Original),
+                            (Span::new(11, 12), Source::Added)]);
+            assert_eq!(Text::new().segments().count(), 0);
+            assert_eq!(Text::new().iter_segments().count(), 0);
+            let mut t = Text::new();
+            t.insert(0, b"typed");
+            assert_eq!(t.segments().collect::<Vec<_>>(), vec![(Span::new(0, 5), Source::Added)]);
+        } 
+
+        #[test]
+        fn iter_segments() {
+            let mut t = Text::from_mmap(b"Hello World");
+            t.insert(5, b",");
+            t.append(b"!");
+            t.delete(0, 1);
+            let segments = t.iter_segments().collect::<Vec<_>>();
+            assert_eq!(segments, vec![(0, &b"ello"[..]), (4, b","), (5, b" World"), (11, b"!")]);
+            assert!(segments.windows(2).all(|w| w[0].0 + w[0].1.len() as Offset == w[1].0));
+            assert_eq!(segments.iter().map(|s| s.1).collect::<Vec<_>>().concat(), t.to_vec());
+        } 
+
+        #[test]
+        fn locate() {
+            let mut t = Text::from_mmap(b"Hello World");
+            t.insert(5, b",");
+            // "Hello" "," " World"
+            let located = (0..t.len()).map(|o| t.locate(o).unwrap()).collect::<Vec<_>>();
+            assert_eq!(&located[..7], &[(0, 5), (0, 5), (0, 5), (0, 5), (0, 5), (5, 6), (6, 12)]);
+            assert!(located[6..].iter().all(|&l| l == (6, 12)));
+            assert_eq!(t.locate(t.len()), None);
+            assert_eq!(t.locate(100), None);
+            assert_eq!(Text::new().locate(0), None);
+        } 
+
+        #[test]
+        fn offset_of_span() {
+            let mut t = Text::from_mmap(b"Hello World");
+            t.insert(5, b",");
+            let comma = t.spans().nth(1).unwrap().1;
+            assert_eq!(t.offset_of_span(comma), Some(5));
+            t.insert(0, b">> ");
+            assert_eq!(t.offset_of_span(comma), Some(8));
+            assert_eq!(t.offset_of_span(Span::new(0, 5)), Some(3));
+            assert_eq!(t.offset_of_span(Span::new(0, 4)), None);
+            t.delete(8, 9);
+            assert_eq!(t.offset_of_span(comma), None);
+            t.undo();
+            assert_eq!(t.offset_of_span(comma), Some(8));
+        } 
+    } 
+
+    mod byte_indices {
+        use super::super::*;
+
+        #[test]
+        fn basics() {
+            assert_eq!(Text::new().byte_indices().next(), None);
+            let mut t = Text::from("Hello World");
+            t.insert(5, b",");
+            t.delete(0, 1);
+            t.append(b"!");
+            let v = t.byte_indices().collect::<Vec<_>>();
+            assert_eq!(t.byte_indices().len(), index(t.len()));
+            assert!(v.iter().map(|&(o, _)| o).eq(0..t.len()));
+            assert!(v.iter().map(|&(_, b)| b).eq(t.to_vec()));
+            assert_eq!(v[4], (4, b','));
+        } 
+    } 
+
+    mod bytes_rev {
+        use super::super::*;
+        use super::Rng;
+
+        #[test]
+        fn basics() {
+            let mut t = Text::new();
+            assert_eq!(t.bytes_rev().next(), None);
+            t.insert(0, &[3, 4]);
+            t.insert(0, &[0, 1, 2]);
+            assert_eq!(t.bytes_rev().collect::<Vec<_>>(), vec![4, 3, 2, 1, 0]);
+            assert_eq!(t.bytes_rev().len(), 5);
+        } 
+
+        #[test]
+        fn random_edits() {
+            for seed in 0..20 {
+                let mut rng = Rng::new(seed);
+                let mut t = Text::new();
+                for i in 0..50 {
+                    let len = t.len();
+                    if len > 0 && rng.below(3) == 0 {
+                        let off1 = rng.below(len);
+                        let off2 = off1 + rng.below(len - off1) + 1;
+                        t.delete(off1, off2);
+                    } else {
+                        let off = rng.below(len + 1);
+                        let bytes = vec![i as u8; index(rng.below(4)) + 1];
+                        t.insert(off, &bytes);
+                    } 
+                    let mut forward = t.bytes().collect::<Vec<_>>();
+                    forward.reverse();
+                    assert_eq!(t.bytes_rev().collect::<Vec<_>>(), forward);
+                } 
+            } 
+        } 
+    } 
+
+    mod binary {
+        use super::super::*;
+
+        #[test]
+        fn hexdump() {
+            let mut t = Text::from(&b"Hello World\n\x01\xffabc"[..]);
+            t.insert(12, b"\0");
+            assert_eq!(t.hexdump(0, t.len()),
+                       "00000000: 4865 6c6c 6f20 576f 726c 640a 0001 ff61  Hello World....a\n\
+                        00000010: 6263                                     bc\n");
+            assert_eq!(t.hexdump_with(0, t.len(), 5),
+                       "00000000: 4865 6c6c 6f  Hello\n\
+                        00000005: 2057 6f72 6c   Worl\n\
+                        0000000a: 640a 0001 ff  d....\n\
+                        0000000f: 6162 63       abc\n");
+            assert_eq!(t.hexdump_with(6, 9, 4), "00000006: 576f 72    Wor\n");
+            assert_eq!(t.hexdump(3, 3), "");
+        } 
+
+        #[test]
+        fn is_probably_binary() {
+            assert!(!Text::new().is_probably_binary());
+            assert!(!Text::from("Hello\tWorld\r\n").is_probably_binary());
+            assert!(!Text::from("Grüße\n").is_probably_binary());
+            assert!(Text::from(&b"ELF\0\x01"[..]).is_probably_binary());
+            assert!(Text::from(&b"\x01\x02\x03abc"[..]).is_probably_binary());
+            assert!(!Text::from(&b"\x1b[1mbold\x1b[0m"[..]).is_probably_binary());
+            // only the first cap bytes count
+            let mut t = Text::from("a".repeat(100).as_str());
+            t.push(b"\0");
+            assert!(t.is_probably_binary());
+            assert!(!t.is_probably_binary_with(100, 0.3));
+            let t = Text::from(&b"\x01\x02abcdefgh"[..]);
+            assert!(!t.is_probably_binary_with(100, 0.3));
+            assert!(t.is_probably_binary_with(100, 0.1));
+            assert!(t.is_probably_binary_with(2, 0.3));
+        } 
+    } 
+
+    mod word_boundaries {
+        use super::super::*;
+
+        #[test]
+        fn ascii() {
+            let mut t = Text::from("foo  bar");
+            t.insert(4, b"\tbaz ");
+            // "foo \tbaz  bar"
+            let next = (0..=t.len()).map(|o| t.next_word_boundary(o)).collect::<Vec<_>>();
+            assert_eq!(next, vec![3, 3, 3, 5, 5, 8, 8, 8, 10, 10, 13, 13, 13, 13]);
+            let prev = (0..=t.len()).map(|o| t.prev_word_boundary(o)).collect::<Vec<_>>();
+            assert_eq!(prev, vec![0, 0, 0, 0, 3, 3, 5, 5, 5, 8, 8, 10, 10, 10]);
+            assert_eq!(t.next_word_boundary(100), t.len());
+            assert_eq!(t.prev_word_boundary(100), 10);
+            assert_eq!(Text::new().next_word_boundary(0), 0);
+            assert_eq!(Text::new().prev_word_boundary(0), 0);
+        } 
+
+        #[test]
+        fn custom_classes() {
+            let t = Text::from("a.b, c");
+            let class = |b: u8| if b.is_ascii_alphanumeric() { 0 } else if b == b' ' { 1 } else { 2 };
+            assert_eq!(t.next_word_boundary_with(0, class), 1);
+            assert_eq!(t.next_word_boundary_with(1, class), 2);
+            assert_eq!(t.next_word_boundary_with(3, class), 4);
+            assert_eq!(t.prev_word_boundary_with(6, class), 5);
+            assert_eq!(t.prev_word_boundary_with(4, class), 3);
+            assert_eq!(t.next_word_boundary(0), 4);
+        } 
+    } 
+
+    mod match_bracket {
+        use super::super::*;
+
+        #[test]
+        fn nested() {
+            let t = Text::from("f(a[0], {b}) (");
+            assert_eq!(t.match_bracket(1), Some(11));
+            assert_eq!(t.match_bracket(11), Some(1));
+            assert_eq!(t.match_bracket(3), Some(5));
+            assert_eq!(t.match_bracket(5), Some(3));
+            assert_eq!(t.match_bracket(8), Some(10));
+            assert_eq!(t.match_bracket(10), Some(8));
+            assert_eq!(t.match_bracket(0), None);
+            assert_eq!(t.match_bracket(13), None);
+            assert_eq!(t.match_bracket(14), None);
+            assert_eq!(Text::from(")(").match_bracket(0), None);
+        } 
+
+        #[test]
+        fn across_pieces() {
+            let mut t = Text::from_mmap(b"(())");
+            t.insert(2, b"[(x)]");
+            t.insert(0, b"{");
+            t.append(b"}");
+            // "{(([(x)]))}"
+            assert_eq!(t.piece_count(), 5);
+            assert_eq!(t.match_bracket(0), Some(10));
+            assert_eq!(t.match_bracket(10), Some(0));
+            assert_eq!(t.match_bracket(1), Some(9));
+            assert_eq!(t.match_bracket(9), Some(1));
+            assert_eq!(t.match_bracket(2), Some(8));
+            assert_eq!(t.match_bracket(8), Some(2));
+            assert_eq!(t.match_bracket(4), Some(6));
+            t.delete(4, 5);
+            // "{(([x)]))}"
+            assert_eq!(t.match_bracket(3), Some(6));
+            assert_eq!(t.match_bracket(1), Some(7));
+            assert_eq!(t.match_bracket(8), None);
+        } 
+    } 
+
+    mod chars {
+        use super::super::*;
+
+        #[test]
+        fn basics() {
+            let mut t = Text::new();
+            t.insert(0, "h€llo wörld".as_bytes());
+            let chars = t.chars().map(|c| c.unwrap()).collect::<String>();
+            assert_eq!(chars, "h€llo wörld");
+        } 
+
+        #[test]
+        fn split_char() {
+            let mut t = Text::new();
+            t.insert(0, "ab".as_bytes());
+            // "é" is 0xC3 0xA9, insert it in two pieces
+            t.insert(1, &[0xA9]);
+            t.insert(1, &[0xC3]);
+            assert_eq!(t.chars().collect::<Vec<_>>(), vec![Ok('a'), Ok('é'), Ok('b')]);
+        } 
+
+        #[test]
+        fn invalid() {
+            let mut t = Text::new();
+            t.insert(0, &[b'a', 0xFF, 0xC3, b'b', 0xE2, 0x82]);
+            let chars = t.chars().collect::<Vec<_>>();
+            assert_eq!(chars.len(), 5);
+            assert_eq!(chars[0], Ok('a'));
+            assert_eq!(chars[1].unwrap_err().bytes(), &[0xFF]);
+            assert_eq!(chars[2].unwrap_err().bytes(), &[0xC3]);
+            assert_eq!(chars[3], Ok('b'));
+            assert_eq!(chars[4].unwrap_err().bytes(), &[0xE2, 0x82]);
+        } 
+
+        #[test]
+        fn utf16() {
+            let mut t = Text::from("a😀b");
+            t.insert(1, "é".as_bytes());
+            // "aé😀b": 1 + 2 + 4 + 1 bytes, 1 + 1 + 2 + 1 code units
+            assert_eq!(t.utf16_len(), "aé😀b".encode_utf16().count());
+            assert_eq!(t.utf16_len(), 5);
+            let to_utf16 = (0..=t.len() + 1).map(|o| t.byte_to_utf16(o)).collect::<Vec<_>>();
+            assert_eq!(to_utf16, vec![0, 1, 1, 2, 2, 2, 2, 4, 5, 5]);
+            let to_byte = (0..=6).map(|u| t.utf16_to_byte(u)).collect::<Vec<_>>();
+            assert_eq!(to_byte, vec![0, 1, 3, 3, 7, 8, 8]);
+            // invalid bytes count one each
+            let t = Text::from(&[b'a', 0xFF, 0xE2, 0x82, b'b'][..]);
+            assert_eq!(t.utf16_len(), 5);
+            assert_eq!(t.byte_to_utf16(4), 4);
+            assert_eq!(t.utf16_to_byte(4), 4);
+            assert_eq!(Text::new().utf16_len(), 0);
+        } 
+    } 
+
+    mod graphemes {
+        use super::super::*;
+
+        fn clusters(t: &Text) -> Vec<String> {
+            t.graphemes()
+                .map(|(off1, off2)| String::from_utf8_lossy(&t.slice(off1, off2)).into_owned())
+                .collect()
+        } 
+
+        #[test]
+        fn basics() {
+            let t = Text::from("ae\u{301}\r\nx\n\n\u{D55C}\u{1100}\u{1161}\u{11A8}");
+            assert_eq!(clusters(&t), vec!["a", "e\u{301}", "\r\n", "x", "\n", "\n",
+                                          "\u{D55C}", "\u{1100}\u{1161}\u{11A8}"]);
+            assert_eq!(Text::new().graphemes().next(), None);
+        } 
+
+        #[test]
+        fn flags_across_pieces() {
+            let mut t = Text::new();
+            // German and French flag, each a pair of regional indicators
+            t.insert(0, "\u{1F1E9}".as_bytes());
+            t.insert(4, "\u{1F1EA}\u{1F1EB}".as_bytes());
+            t.insert(12, "\u{1F1F7}!".as_bytes());
+            assert_eq!(t.graphemes().collect::<Vec<_>>(), vec![(0, 8), (8, 16), (16, 17)]);
+        } 
+
+        #[test]
+        fn emoji_sequences() {
+            let mut t = Text::new();
+            // family: man ZWJ woman ZWJ girl, split in the middle of the
+            // second ZWJ
+            let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}".as_bytes();
+            t.insert(0, &family[12..]);
+            t.insert(0, &family[..12]);
+            // thumbs up with skin tone, then a ZWJ not followed by an emoji
+            t.insert(t.len(), "\u{1F44D}\u{1F3FD}a\u{200D}b".as_bytes());
+            assert_eq!(clusters(&t), vec!["\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}",
+                                          "\u{1F44D}\u{1F3FD}", "a\u{200D}", "b"]);
+        } 
+
+        #[test]
+        fn invalid() {
+            let t = Text::from(&[b'a', 0xCC, 0x81, 0xFF, 0xCC, 0x81][..]);
+            assert_eq!(t.graphemes().collect::<Vec<_>>(), vec![(0, 3), (3, 4), (4, 6)]);
+        } 
+    } 
+
+    mod diff {
+        use super::super::*;
+        use super::Rng;
+
+        #[test]
+        fn inserted_word() {
+            let a = Text::from("the quick fox");
+            let b = Text::from("the quick brown fox");
+            let edits = a.diff(&b);
+            assert_eq!(edits, vec![Edit::Insert { off: 10, bytes: b"brown ".to_vec() }]);
+            let mut c = a.clone();
+            c.apply_edits(&edits).unwrap();
+            assert_eq!(c, b);
+            assert_eq!(b.diff(&a), vec![Edit::Delete { off: 10, len: 6 }]);
+            assert!(a.diff(&a).is_empty());
+        } 
+
+        #[test]
+        fn replacement() {
+            let a = Text::from("abcxyzdef");
+            let b = Text::from("abc12def");
+            let edits = a.diff(&b);
+            assert_eq!(edits, vec![Edit::Insert { off: 3, bytes: b"12".to_vec() },
+                                   Edit::Delete { off: 3, len: 3 }]);
+            assert_eq!(Text::new().diff(&b), vec![Edit::Insert { off: 0, bytes: b.to_vec() }]);
+            assert_eq!(b.diff(&Text::new()), vec![Edit::Delete { off: 0, len: 8 }]);
+            let mut c = a.clone();
+            c.apply_edits(&edits).unwrap();
+            assert_eq!(c, b);
+        } 
+
+        #[test]
+        fn apply_edits() {
+            let mut t = Text::from("Hello World");
+            // all against the original text
+            t.apply_edits(&[Edit::Delete { off: 0, len: 1 },
+                            Edit::Insert { off: 0, bytes: b"J".to_vec() },
+                            Edit::Insert { off: 5, bytes: b",".to_vec() },
+                            Edit::Delete { off: 5, len: 1 },
+                            Edit::Insert { off: 11, bytes: b"!".to_vec() }]).unwrap();
+            assert_eq!(t.to_utf8_string().unwrap(), "Jello,World!");
+            // the edits are one undo step
+            assert!(t.undo());
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello World");
+            assert!(t.redo());
+            // an offset inside a delete moves to its start
+            t.apply_edits(&[Edit::Delete { off: 1, len: 4 },
+                            Edit::Insert { off: 3, bytes: b"-".to_vec() }]).unwrap();
+            assert_eq!(t.to_utf8_string().unwrap(), "J-,World!");
+            assert_eq!(t.apply_edits(&[Edit::Insert { off: 3, bytes: b"x".to_vec() },
+                                       Edit::Delete { off: 1, len: 1 }]),
+                       Err(EditError { index: 1, error: OffsetError::Reversed { off1: 3, off2: 1 } }));
+            assert_eq!(t.to_utf8_string().unwrap(), "J-,World!");
+            // overlapping deletes delete the union of their ranges
+            t.apply_edits(&[Edit::Delete { off: 1, len: 3 },
+                            Edit::Delete { off: 2, len: 4 }]).unwrap();
+            assert_eq!(t.to_utf8_string().unwrap(), "Jld!");
+            let mut t = Text::from("Hello");
+            assert_eq!(t.apply_edits(&[Edit::Delete { off: 1, len: Offset::MAX }]),
+                       Err(EditError { index: 0, error: OffsetError::OutOfBounds { off: Offset::MAX, len: 5 } }));
+            assert_eq!(t.apply_edits(&[Edit::Insert { off: 1, bytes: b"x".to_vec() },
+                                       Edit::Delete { off: Offset::MAX, len: 1 }]),
+                       Err(EditError { index: 1, error: OffsetError::OutOfBounds { off: Offset::MAX, len: 5 } }));
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello");
+        } 
+
+        #[test]
+        fn apply_edits_fails_atomically() {
+            let mut t = Text::from("Hello World");
+            t.insert(5, b",");
+            assert!(t.undo());
+            let before = t.to_vec();
+            let edits = [Edit::Insert { off: 0, bytes: b">> ".to_vec() },
+                         Edit::Delete { off: 6, len: 5 },
+                         Edit::Delete { off: 8, len: 4 },
+                         Edit::Insert { off: 11, bytes: b"!".to_vec() }];
+            assert_eq!(t.apply_edits(&edits),
+                       Err(EditError { index: 2, error: OffsetError::OutOfBounds { off: 12, len: 11 } }));
+            assert_eq!(t.to_vec(), before);
+            // nothing happened, so the undone insert can still be redone
+            assert!(t.redo());
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello, World");
+        } 
+
+        #[test]
+        fn random() {
+            let mut rng = Rng::new(11);
+            for _ in 0..100 {
+                let a: Vec<u8> = (0..rng.below(30)).map(|_| b'a' + rng.below(3) as u8).collect();
+                let b: Vec<u8> = (0..rng.below(30)).map(|_| b'a' + rng.below(3) as u8).collect();
+                let (a, b) = (Text::from(a), Text::from(b));
+                let edits = a.diff(&b);
+                let mut c = a.clone();
+                c.apply_edits(&edits).unwrap();
+                assert_eq!(c, b);
+                let changed: Offset = edits.iter().map(|e| match *e {
+                    Edit::Insert { ref bytes, .. } => bytes.len() as Offset,
+                    Edit::Delete { len, .. } => len,
+                }).sum();
+                // a shortest edit script keeps a longest common subsequence
+                let (a, b) = (a.to_vec(), b.to_vec());
+                let mut lcs = vec![vec![0; b.len() + 1]; a.len() + 1];
+                for i in 0..a.len() {
+                    for j in 0..b.len() {
+                        lcs[i + 1][j + 1] = if a[i] == b[j] {
+                            lcs[i][j] + 1
+                        } else {
+                            std::cmp::max(lcs[i][j + 1], lcs[i + 1][j])
+                        };
+                    } 
+                } 
+                let keep = lcs[a.len()][b.len()];
+                assert_eq!(changed, a.len() as Offset + b.len() as Offset - 2 * keep);
+            } 
+        } 
+    } 
+
+    mod display {
+        use super::super::*;
+        use super::Rng;
+
+        #[test]
+        fn basics() {
+            let mut t = Text::from("ab");
+            t.insert(1, &[0xA9]);
+            t.insert(1, &[0xC3]);
+            assert_eq!(format!("{}", t), "aéb");
+            t.insert(0, &[0xFF]);
+            t.insert(4, &[0xE2, 0x82]);
+            assert_eq!(t.to_string(), "\u{FFFD}aé\u{FFFD}b");
+        } 
+
+        #[test]
+        fn random_pieces_match_from_utf8_lossy() {
+            let fragments: &[&[u8]] = &[b"a", b"\xC3", b"\xA9", b"\xE2\x82", b"\xAC",
+                                        b"\xF0\x9F", b"\x98\x80", b"\xFF", "€".as_bytes()];
+            let mut rng = Rng::new(7);
+            for _ in 0..200 {
+                let mut t = Text::new();
+                for _ in 0..rng.below(12) {
+                    let off = rng.below(t.len() + 1);
+                    t.insert(off, fragments[index(rng.below(fragments.len() as Offset))]);
+                } 
+                assert_eq!(t.to_string(), String::from_utf8_lossy(&t.to_vec()));
+            } 
+        } 
+
+        #[test]
+        fn to_string_lossy() {
+            let mut t = Text::from("ok");
+            t.insert(1, &[0xFF]);
+            assert_eq!(t.to_string_lossy(), "o\u{FFFD}k");
+            assert!(t.to_utf8_string().is_err());
+            assert_eq!(Text::new().to_string_lossy(), "");
+        } 
+
+        #[test]
+        fn debug_pieces() {
+            let mut t = Text::from("Hello");
+            t.insert(5, b"\n\"!\xFF");
+            assert_eq!(t.debug_pieces(), "0..5 -> \"Hello\"\n5..9 -> \"\\n\\\"!\\xff\"\n");
+        } 
+    } 
+
+    mod add {
+        use super::super::*;
+
+        #[test]
+        fn basics() {
+            let mut a = Text::from("Hello");
+            a.insert(0, b">> ");
+            let mut b = Text::from("World");
+            b.insert(0, b", ");
+            let mut expected = a.to_vec();
+            expected.extend(b.to_vec());
+            let c = a + &b;
+            assert_eq!(c.to_vec(), expected);
+            let mut c = c + &Text::new();
+            c += &b;
+            assert_eq!(c.to_utf8_string().unwrap(), ">> Hello, World, World");
+            assert_eq!(b.to_utf8_string().unwrap(), ", World");
+            assert!(c.undo());
+            assert_eq!(c.to_utf8_string().unwrap(), ">> Hello, World");
+        } 
+    } 
+
+    mod text_eq {
+        use super::super::*;
+        use super::Rng;
+
+        #[test]
+        #[allow(clippy::cmp_owned)]
+        fn basics() {
+            let mut a = Text::new();
+            a.insert(0, "World".as_bytes());
+            a.insert(0, "Hello ".as_bytes());
+            let b = Text::from("Hello World");
+            assert_eq!(a, b);
+            assert!(a != Text::from("Hello World!"));
+            assert!(a != Text::from("Hello Wprld"));
+            assert_eq!(Text::new(), Text::from(""));
+        } 
+
+        #[test]
+        fn bytes_and_strs() {
+            let mut t = Text::from("World");
+            t.insert(0, b"Hello ");
+            assert_eq!(t, "Hello World");
+            assert!(t == *"Hello World");
+            assert!(t == b"Hello World"[..]);
+            assert_eq!(t, &b"Hello World"[..]);
+            assert!(t != "Hello World!");
+            assert!(t != "Hello Wprld");
+            assert!(t != b"Hello"[..]);
+            assert_eq!(Text::new(), "");
+        } 
+
+        #[test]
+        fn random_edits() {
+            for seed in 0..20 {
+                let mut rng = Rng::new(seed);
+                let mut t = Text::new();
+                let mut v = Vec::new();
+                for i in 0..30 {
+                    let len = t.len();
+                    if len > 0 && rng.below(3) == 0 {
+                        let off1 = rng.below(len);
+                        let off2 = off1 + rng.below(len - off1) + 1;
+                        t.delete(off1, off2);
+                        v.drain(index(off1)..index(off2));
+                    } else {
+                        let off = rng.below(len + 1);
+                        let bytes = vec![i as u8; index(rng.below(4)) + 1];
+                        t.insert(off, &bytes);
+                        for (k, &b) in bytes.iter().enumerate() {
+                            v.insert(index(off) + k, b);
+                        } 
+                    } 
+                    assert_eq!(t, Text::from(v.clone()));
+                } 
+            } 
+        } 
+
+        #[test]
+        fn hash() {
+            use std::collections::HashSet;
+            let mut a = Text::new();
+            a.insert(0, "World".as_bytes());
+            a.insert(0, "Hello ".as_bytes());
+            let b = Text::from("Hello World");
+            let mut set = HashSet::new();
+            set.insert(a);
+            set.insert(b);
+            assert_eq!(set.len(), 1);
+            set.insert(Text::from("Hello"));
+            assert_eq!(set.len(), 2);
+        } 
+    } 
+
+    mod piece_tree {
+        use super::super::*;
+        use super::Rng;
+        use std::time::Instant;
+
+        #[test]
+        fn random_edits_and_undo() {
+            for seed in 0..20 {
+                let mut rng = Rng::new(seed);
+                let mut t = Text::new();
+                // states[i] is the content after i edits, current is
+                // the index of the current state
+                let mut states = vec![Vec::new()];
+                let mut current = 0;
+                for i in 0..60 {
+                    let len = t.len();
+                    match rng.below(6) {
+                        0 => {
+                            if t.undo() {
+                                current -= 1;
+                            } 
+                        } 
+                        1 => {
+                            if t.redo() {
+                                current += 1;
+                            } 
+                        } 
+                        2 => {
+                            t.coalesce();
+                        } 
+                        _ => {
+                            let off1 = rng.below(len + 1);
+                            let off2 = off1 + rng.below(len - off1 + 1);
+                            let bytes = vec![i as u8; index(rng.below(3))];
+                            if off1 == off2 && bytes.is_empty() {
+                                continue;
+                            } 
+                            t.replace(off1, off2, &bytes);
+                            let mut v = states[current].clone();
+                            v.splice(index(off1)..index(off2), bytes);
+                            states.truncate(current + 1);
+                            states.push(v);
+                            current += 1;
+                        } 
+                    } 
+                    assert_eq!(t.to_vec(), states[current]);
+                    assert_eq!(t.check_integrity(), Ok(()));
+                    for off in 0..t.len() {
+                        assert_eq!(t.byte_at(off), Some(states[current][index(off)]));
+                    } 
+                } 
+            } 
+        } 
+
+        #[test]
+        fn check_integrity_finds_corruption() {
+            let mut t = Text::from_mmap(b"hello\nworld");
+            t.insert(5, b", big");
+            assert_eq!(t.check_integrity(), Ok(()));
+            let (_, p) = t.pieces().nth(1).unwrap();
+
+            let mut u = t.clone();
+            u.pieces[p.0].span.off2 += 100;
+            match u.check_integrity() {
+                Err(IntegrityError::SpanOutOfBuffer { piece, .. }) => assert_eq!(piece, p.0),
+                r => panic!("unexpected {:?}", r),
+            } 
+
+            let mut u = t.clone();
+            u.pieces[p.0].newlines = 7;
+            assert_eq!(u.check_integrity(),
+                       Err(IntegrityError::NewlineCount { piece: p.0, cached: 7, actual: 0 }));
+
+            let mut u = t.clone();
+            u.len += 1;
+            assert_eq!(u.check_integrity(), Err(IntegrityError::Length { walked: 16, len: 17 }));
+
+            let mut u = t.clone();
+            let last = u.pieces[SENTINEL.0].prev;
+            u.pieces[last.0].next = p;
+            assert_eq!(u.check_integrity(), Err(IntegrityError::Cycle { forward: true }));
+
+            let mut u = t.clone();
+            u.pieces[SENTINEL.0].prev = p;
+            assert_eq!(u.check_integrity(), Err(IntegrityError::WalksDiffer));
+
+            let mut u = t.clone();
+            u.pieces[p.0].weight += 1;
+            assert_eq!(u.check_integrity(), Err(IntegrityError::Tree { piece: p.0 }));
+        } 
+
+        /// The piece containing off, by walking the piece list.  This is
+        /// how find_piece used to work.
+        fn find_piece_linear(t: &Text, off: Offset) -> (Offset, Piece) {
+            let mut found = (off, SENTINEL);
+            for (s, p) in t.pieces() {
+                if s > off {
+                    break;
+                } 
+                found = (s, p);
+            } 
+            found
+        } 
+
+        /// Run with cargo test --release -- --ignored --nocapture
+        #[test]
+        #[ignore]
+        fn bench_random_inserts() {
+            let n = 100000;
+            let mut rng = Rng::new(42);
+            let mut t = Text::new();
+            let start = Instant::now();
+            for _ in 0..n {
+                let off = rng.below(t.len() + 1);
+                t.insert(off, "x".as_bytes());
+            } 
+            println!("{} random inserts: {:?}", n, start.elapsed());
+            let lookups = 1000;
+            let offsets = (0..lookups).map(|_| rng.below(t.len())).collect::<Vec<_>>();
+            let start = Instant::now();
+            for &off in offsets.iter() {
+                assert!(t.find_piece(off).1 != SENTINEL);
+            } 
+            println!("{} lookups in the tree: {:?}", lookups, start.elapsed());
+            let start = Instant::now();
+            for &off in offsets.iter() {
+                assert!(find_piece_linear(&t, off).1 != SENTINEL);
+            } 
+            println!("{} lookups walking the list: {:?}", lookups, start.elapsed());
+        } 
+
+        /// What checking the invariant after every edit costs.  Run with
+        /// cargo test --release -- --ignored --nocapture
+        #[test]
+        #[ignore]
+        fn bench_edits_with_invariant() {
+            let n = 10000;
+            for &check in [false, true].iter() {
+                let mut rng = Rng::new(42);
+                let mut t = Text::new();
+                let start = Instant::now();
+                for _ in 0..n {
+                    let off = rng.below(t.len() + 1);
+                    t.insert(off, "xy".as_bytes());
+                    let off = rng.below(t.len());
+                    t.delete(off, off + 1);
+                    if check {
+                        t.check_integrity().unwrap();
+                    } 
+                } 
+                println!("{} edits {} checking the invariant: {:?}",
+                         2 * n, if check { "with" } else { "without" }, start.elapsed());
+            } 
+        } 
+    } 
+
+    mod text {
+        use super::super::*;
+        use super::Rng;
+
+        #[test]
+        fn insert_beginning() {
+            let mut t = Text::new();
+            assert_eq!(t.len(), 0);
+            t.insert(0, "World".as_bytes());
+            assert_eq!(t.len(), 5);
+            assert_eq!(t.to_utf8_string().unwrap(), "World");
+            t.insert(0, "Hello ".as_bytes());
+            assert_eq!(t.len(), 11);
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello World");
+            t.insert(0, "...".as_bytes());
+            assert_eq!(t.len(), 14);
+            assert_eq!(t.to_utf8_string().unwrap(), "...Hello World");
+        } 
+
+        #[test]
+        fn try_insert_and_delete() {
+            let mut t = Text::from("Hello");
+            assert_eq!(t.try_insert(6, b"!"),
+                       Err(OffsetError::OutOfBounds { off: 6, len: 5 }));
+            assert_eq!(t.try_insert(6, b""),
+                       Err(OffsetError::OutOfBounds { off: 6, len: 5 }));
+            assert_eq!(t.try_delete(3, 2),
+                       Err(OffsetError::Reversed { off1: 3, off2: 2 }));
+            assert_eq!(t.try_delete(2, 9),
+                       Err(OffsetError::OutOfBounds { off: 9, len: 5 }));
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello");
+            assert!(!t.undo());
+            assert_eq!(t.try_insert(5, b"!"), Ok(()));
+            assert_eq!(t.try_delete(0, 1), Ok(()));
+            assert_eq!(t.try_delete(1, 1), Ok(()));
+            assert_eq!(t.to_utf8_string().unwrap(), "ello!");
+            t.delete(5, 2);
+            t.delete(9, 9);
+            assert_eq!(t.to_utf8_string().unwrap(), "ello!");
+            assert_eq!(t.edit_count(), 2);
+        } 
+
+        #[test]
+        fn insert_text() {
+            let mut rng = Rng::new(3);
+            let mut other = Text::from("original");
+            for i in 0..20 {
+                let off = rng.below(other.len() + 1);
+                other.insert(off, &[b'0' + i % 10]);
+            } 
+            other.delete(3, 7);
+            assert!(other.pieces().count() > 10);
+            let mut t = Text::from("Hello World");
+            t.insert(5, b",");
+            t.insert_text(3, &other);
+            let mut expected = b"Hel".to_vec();
+            expected.extend(other.to_vec());
+            expected.extend(b"lo, World");
+            assert_eq!(t.to_vec(), expected);
+            t.insert_text(0, &Text::new());
+            t.insert_text(t.len(), &Text::from("!"));
+            expected.push(b'!');
+            assert_eq!(t.to_vec(), expected);
+            assert!(t.undo());
+            assert!(t.undo());
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello, World");
+        } 
+
+        #[test]
+        fn clear() {
+            let mut t = Text::from("Hello");
+            t.append(&[b'!'; 5000]);
+            let m = t.add_marker(3);
+            let s = t.snapshot();
+            let capacity = t.stats().buffer_capacity;
+            t.clear();
+            assert!(t.is_empty());
+            assert_eq!(t.pieces.len(), 1);
+            assert_eq!(t.stats().buffer_capacity, capacity);
+            assert_eq!(t.marker_offset(m), 0);
+            assert!(!t.undo());
+            assert!(!t.restore(&s));
+            t.insert(0, b"again");
+            assert_eq!(t.to_utf8_string().unwrap(), "again");
+            assert_eq!(t.stats().buffer_capacity, capacity);
+            assert!(t.undo());
+            assert!(t.is_empty());
+        } 
+
+        #[test]
+        fn is_piece_boundary() {
+            let mut t = Text::from("Hello World");
+            assert!(t.is_piece_boundary(0));
+            assert!(!t.is_piece_boundary(5));
+            assert!(t.is_piece_boundary(11));
+            assert!(!t.is_piece_boundary(12));
+            t.insert(5, b",");
+            assert!(t.is_piece_boundary(0));
+            assert!(t.is_piece_boundary(5));
+            assert!(t.is_piece_boundary(6));
+            assert!(!t.is_piece_boundary(7));
+            assert!(t.is_piece_boundary(12));
+            t.coalesce();
+            assert!(t.is_piece_boundary(6));
+            assert!(Text::new().is_piece_boundary(0));
+        } 
+
+        #[test]
+        fn piece_count_and_spans() {
+            let mut t = Text::new();
+            assert_eq!(t.piece_count(), 0);
+            assert_eq!(t.spans().count(), 0);
+            t.insert(0, b"World");
+            t.insert(0, b"Hello ");
+            t.insert(5, b",");
+            t.delete(0, 1);
+            assert_eq!(t.to_utf8_string().unwrap(), "ello, World");
+            assert_eq!(t.piece_count(), 4);
+            assert_eq!(t.spans().collect::<Vec<_>>(),
+                       vec![(0, Span::new(6, 10)), (4, Span::new(11, 12)),
+                            (5, Span::new(10, 11)), (6, Span::new(0, 5))]);
+        } 
+
+        #[test]
+        fn truncate() {
+            let mut t = Text::from("Hello");
+            t.append(b" World");
+            t.truncate(20);
+            t.truncate(11);
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello World");
+            // inside a piece
+            t.truncate(8);
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello Wo");
+            // at a piece boundary
+            t.truncate(5);
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello");
+            assert_eq!(t.pieces().count(), 1);
+            t.truncate(0);
+            assert!(t.is_empty());
+            assert!(t.undo());
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello");
+        } 
+
+        #[test]
+        fn append_text() {
+            let mut a = Text::from("Hello");
+            a.insert(0, b">> ");
+            let mut b = Text::from("World");
+            b.insert(0, b", ");
+            b.append(b"!");
+            let mut expected = a.to_vec();
+            expected.extend(b.to_vec());
+            a.append_text(b);
+            assert_eq!(a.to_vec(), expected);
+            a.append_text(Text::new());
+            assert_eq!(a.to_utf8_string().unwrap(), ">> Hello, World!");
+        } 
+
+        #[test]
+        fn normalize_newlines() {
+            let mut t = Text::from("a\r\nb\rc\n");
+            let m = t.add_marker(4);
+            assert_eq!(t.normalize_newlines(LineEnding::Lf), 2);
+            assert_eq!(t.to_utf8_string().unwrap(), "a\nb\nc\n");
+            assert_eq!(t.len(), 6);
+            // the marker stays in front of the \r turned \n
+            assert_eq!(t.marker_offset(m), 3);
+            assert_eq!(t.normalize_newlines(LineEnding::Lf), 0);
+            assert_eq!(t.normalize_newlines(LineEnding::CrLf), 3);
+            assert_eq!(t.to_utf8_string().unwrap(), "a\r\nb\r\nc\r\n");
+            assert_eq!(t.marker_offset(m), 6);
+            // each call is one undo step
+            assert!(t.undo());
+            assert_eq!(t.to_utf8_string().unwrap(), "a\nb\nc\n");
+            assert!(t.undo());
+            assert_eq!(t.to_utf8_string().unwrap(), "a\r\nb\rc\n");
+            assert!(!t.undo());
+            assert!(t.redo());
+            assert_eq!(t.to_utf8_string().unwrap(), "a\nb\nc\n");
+            // a \r at the end of one piece and a \n at the start of the next
+            let mut t = Text::from("x\n");
+            t.insert(1, b"\r");
+            t.insert(0, b"\r");
+            assert_eq!(t.normalize_newlines(LineEnding::Cr), 1);
+            assert_eq!(t.to_utf8_string().unwrap(), "\rx\r");
+            assert!(t.undo());
+            assert_eq!(t.to_utf8_string().unwrap(), "\rx\r\n");
+        } 
+
+        #[test]
+        fn strip_prefix_and_suffix() {
+            let mut t = Text::from("World");
+            t.insert(0, b"Hello ");
+            t.append(b"!\n");
+            assert!(!t.strip_prefix(b"Hello, "));
+            assert!(t.strip_prefix(b"Hello W"));
+            assert_eq!(t.to_utf8_string().unwrap(), "orld!\n");
+            assert!(!t.strip_suffix(b"d\n"));
+            assert!(t.strip_suffix(b"ld!\n"));
+            assert_eq!(t.to_utf8_string().unwrap(), "or");
+            assert!(t.strip_prefix(b""));
+            assert!(t.strip_suffix(b""));
+            assert!(!t.strip_prefix(b"ore"));
+            assert!(!t.strip_suffix(b"xor"));
+            assert!(t.strip_suffix(b"or"));
+            assert!(t.is_empty());
+        } 
+
+        #[test]
+        fn starts_and_ends_with() {
+            let mut t = Text::from_mmap(b"#!/bin/sh\necho hi");
+            t.append(b"\n");
+            // the tail "hi\n" is in two pieces
+            assert_eq!(t.piece_count(), 2);
+            assert!(t.ends_with(b"hi\n"));
+            assert!(t.ends_with(b"\n"));
+            assert!(!t.ends_with(b"hi"));
+            assert!(!t.ends_with(b"ho\n"));
+            assert!(t.starts_with(b"#!"));
+            assert!(!t.starts_with(b"#?"));
+            assert!(t.starts_with(b""));
+            assert!(t.ends_with(b""));
+            let all = t.to_vec();
+            assert!(t.starts_with(&all) && t.ends_with(&all));
+            let mut longer = all.clone();
+            longer.push(b'x');
+            assert!(!t.starts_with(&longer) && !t.ends_with(&longer));
+            assert!(Text::new().starts_with(b"") && Text::new().ends_with(b""));
+            assert!(!Text::new().ends_with(b"\n"));
+        } 
+
+        #[test]
+        fn retain() {
+            let mut t = Text::from("a\r\nb\r\n");
+            let m = t.add_marker(4);
+            t.retain(|b| b != b'\r');
+            assert_eq!(t.to_utf8_string().unwrap(), "a\nb\n");
+            assert_eq!(t.marker_offset(m), 3);
+            let mut t = Text::from("ab\x01\x02c");
+            t.insert(3, b"\x7f\td");
+            t.retain(|b| b == b'\t' || !b.is_ascii_control());
+            assert_eq!(t.to_utf8_string().unwrap(), "ab\tdc");
+            // retain is one undo step
+            assert!(t.undo());
+            assert_eq!(t.to_vec(), b"ab\x01\x7f\td\x02c");
+            assert!(t.redo());
+            let mut lines = Text::from("line\r\n".repeat(100).as_str());
+            lines.retain(|b| b != b'\r');
+            assert_eq!(lines.to_vec(), "line\n".repeat(100).into_bytes());
+            assert!(lines.undo());
+            assert_eq!(lines.to_vec(), "line\r\n".repeat(100).into_bytes());
+            assert!(!lines.undo());
+            t.retain(|_| true);
+            assert_eq!(t.to_utf8_string().unwrap(), "ab\tdc");
+        } 
+
+        #[test]
+        fn replace_all() {
+            let mut t = Text::from("a cat, a hat, a bat");
+            t.insert(7, b"aa ");
+            // "a cat, aa a hat, a bat"
+            assert_eq!(t.replace_all(b"a ", b"one "), 4);
+            assert_eq!(t.to_utf8_string().unwrap(), "one cat, aone one hat, one bat");
+            assert_eq!(t.replace_all(b"one", b"1"), 4);
+            assert_eq!(t.to_utf8_string().unwrap(), "1 cat, a1 1 hat, 1 bat");
+            assert_eq!(t.replace_all(b"at", b"ug"), 3);
+            assert_eq!(t.to_utf8_string().unwrap(), "1 cug, a1 1 hug, 1 bug");
+            assert_eq!(t.replace_all(b"", b"x"), 0);
+            assert_eq!(t.replace_all(b"dog", b"x"), 0);
+            let mut t = Text::from("aaaa");
+            assert_eq!(t.replace_all(b"aa", b"a"), 2);
+            assert_eq!(t.to_utf8_string().unwrap(), "aa");
+            assert!(t.undo());
+            assert_eq!(t.to_utf8_string().unwrap(), "aaaa");
+            assert!(!t.undo());
+        } 
+
+        #[test]
+        fn set_byte() {
+            let mut t = Text::from_mmap(b"Hello World");
+            assert!(!t.set_byte(0, b'J'));
+            t.append(b"!?");
+            assert!(t.set_byte(12, b'\n'));
+            assert!(t.set_byte(11, b'.'));
+            assert!(!t.set_byte(13, b'x'));
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello World.\n");
+            assert_eq!(t.line_count(), 2);
+            assert!(t.set_byte(12, b'!'));
+            assert_eq!(t.line_count(), 1);
+            assert_eq!(t.check_integrity(), Ok(()));
+            // split by the insert, the old piece still refers to the bytes
+            t.insert(12, b"x");
+            assert!(!t.set_byte(11, b'?'));
+            assert!(t.set_byte(12, b'y'));
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello World.y!");
+            assert!(t.undo());
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello World.!");
+            assert!(t.redo());
+            // shared by insert_interned
+            t.insert_interned(0, b"ab");
+            t.insert_interned(4, b"ab");
+            assert!(!t.set_byte(0, b'c'));
+            // compact keeps the sharing, but not the undo pieces
+            t.compact();
+            assert!(!t.set_byte(0, b'c'));
+            assert!(t.set_byte(15, b'?'));
+            assert_eq!(t.to_utf8_string().unwrap(), "abHeabllo World?y!");
+        } 
 
-#[cfg(test)]
-mod tests {
-    mod span {
-        use super::super::*;
+        #[test]
+        fn set_byte_after_appends() {
+            let mut t = Text::new();
+            t.append(b"ab");
+            let s = t.snapshot();
+            t.append(b"cd");
+            // the second append grew the piece, the old one covering "ab"
+            // is kept for undo, writing to its bytes would change s too
+            assert!(!t.set_byte(0, b'x'));
+            assert!(!t.set_byte(1, b'\n'));
+            assert_eq!(t.to_utf8_string().unwrap(), "abcd");
+            assert!(t.restore(&s));
+            assert_eq!(t.to_utf8_string().unwrap(), "ab");
+            assert!(t.redo());
+            assert_eq!(t.check_integrity(), Ok(()));
+        } 
 
         #[test]
-        fn basics() {
-            let s = Span::new(1, 1);
-            assert_eq!(s.len(), 0);
-            assert!(s.is_empty());
-            let s2 = Span::new(3, 7);
-            assert!(s2.len() == 4);
+        fn overwrite() {
+            let mut t = Text::from("Hello World");
+            t.insert(5, b",");
+            let m = t.add_marker(6);
+            t.overwrite(4, b"O; w");
+            assert_eq!(t.to_utf8_string().unwrap(), "HellO; world");
+            assert_eq!(t.marker_offset(m), 6);
+            assert_eq!(t.len(), 12);
+            let pieces = t.piece_count();
+            t.overwrite(5, b"; w");
+            t.overwrite(12, b"");
+            assert_eq!(t.piece_count(), pieces);
+            assert!(t.undo());
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello, World");
+            assert_eq!(t.marker_offset(m), 6);
         } 
 
         #[test]
-        fn split() {
-            let s = Span::new(3, 7);
-            assert_eq!(s.split(0), None);
-            assert_eq!(s.split(4), None);
-            assert_eq!(s.split(3), Some((Span { off1: 3, off2: 6 }, Span { off1: 6, off2: 7 })));
+        #[should_panic(expected = "past the end")]
+        fn overwrite_past_the_end() {
+            let mut t = Text::from("Hello");
+            t.overwrite(3, b"p!!");
         } 
-    } 
 
-    mod append_only_buffer {
-        use super::super::*;
+        #[test]
+        fn delete_ranges() {
+            let mut t = Text::from("Hello, dear World!");
+            t.insert(0, b">> ");
+            let m = t.add_marker(15);
+            t.delete_ranges(&[(16, 20), (0, 3), (8, 10)]);
+            assert_eq!(t.to_utf8_string().unwrap(), "Hellodear W!");
+            assert_eq!(t.marker_offset(m), 10);
+            t.delete_ranges(&[(0, 2), (2, 4), (4, 4)]);
+            assert_eq!(t.to_utf8_string().unwrap(), "odear W!");
+            t.delete_ranges(&[]);
+            // each call is one undo step
+            assert!(t.undo());
+            assert_eq!(t.to_utf8_string().unwrap(), "Hellodear W!");
+            assert!(t.undo());
+            assert_eq!(t.to_utf8_string().unwrap(), ">> Hello, dear World!");
+            assert_eq!(t.marker_offset(m), 15);
+            assert!(t.redo());
+            assert_eq!(t.to_utf8_string().unwrap(), "Hellodear W!");
+            assert!(t.redo());
+            assert_eq!(t.to_utf8_string().unwrap(), "odear W!");
+            assert!(!t.redo());
+        } 
 
-        #[test] 
-        fn basics() {
-            let mut b = AppendOnlyBuffer::new();
-            let bytes = "Hello World".as_bytes();
-            let sp = b.append(bytes);
-            assert_eq!(b.get(sp), bytes);
-            let bytes2 = "Just testing".as_bytes();
-            let sp2 = b.append(bytes2);
-            assert_eq!(b.get(sp), bytes);
-            assert_eq!(b.get(sp2), bytes2);
+        #[test]
+        #[should_panic(expected = "overlap")]
+        fn delete_ranges_overlapping() {
+            let mut t = Text::from("Hello World");
+            t.delete_ranges(&[(0, 3), (6, 8), (2, 4)]);
         } 
-    } 
 
-    mod text {
-        use super::super::*;
+        #[test]
+        fn delete_ranges_checks_first() {
+            let mut t = Text::from("Hello World");
+            let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                t.delete_ranges(&[(0, 3), (6, 20)]);
+            }));
+            assert!(r.is_err());
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello World");
+        } 
 
         #[test]
-        fn insert_beginning() {
+        fn drain() {
+            let mut t = Text::from("Hello World");
+            t.insert(5, b",");
+            t.insert(0, b">> ");
+            let expected = t.slice(1, 10);
+            assert_eq!(t.drain(1, 10), expected);
+            assert_eq!(expected, b"> Hello, ");
+            assert_eq!(t.to_utf8_string().unwrap(), ">World");
+            assert!(t.drain(3, 3).is_empty());
+            assert_eq!(t.drain(0, 6), b">World");
+            assert!(t.is_empty());
+            assert!(t.undo());
+            assert_eq!(t.to_utf8_string().unwrap(), ">World");
+        } 
+
+        #[test]
+        fn insert_interned() {
             let mut t = Text::new();
-            assert_eq!(t.len(), 0);
-            t.insert(0, "World".as_bytes());
-            assert_eq!(t.len(), 5);
-            assert_eq!(t.to_utf8_string().unwrap(), "World");
-            t.insert(0, "Hello ".as_bytes());
-            assert_eq!(t.len(), 11);
+            for i in 0..100 {
+                let level: &[u8] = if i % 3 == 0 { b"WARN " } else { b"INFO " };
+                let off = t.len();
+                t.insert_interned(off, level);
+                let off = t.len();
+                t.insert_interned(off, b"something happened\n");
+            } 
+            assert_eq!(t.stats().buffer_used, 5 + 5 + 19);
+            assert_eq!(t.lines().nth(3), Some(b"WARN something happened".to_vec()));
+            assert_eq!(t.len(), 100 * 24);
+            t.insert(0, b"INFO ");
+            assert_eq!(t.stats().buffer_used, 5 + 5 + 19 + 5);
+            assert_eq!(t.stats().dead_bytes, 0);
+            // shared bytes stay shared
+            assert_eq!(t.compact(), 0);
+            t.insert_interned(0, b"INFO ");
+            assert_eq!(t.slice(0, 10), b"INFO INFO ");
+            assert!(t.undo());
+            assert_eq!(t.slice(0, 10), b"INFO WARN ");
+        } 
+
+        #[test]
+        fn insert_all() {
+            let mut t = Text::from("Hello World");
+            let words = ["there", ", dear", " "];
+            t.insert_all(6, words.iter().map(|w| w.as_bytes()));
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello there, dear World");
+            assert_eq!(t.len(), 23);
+            assert_eq!(t.piece_count(), 3);
+            t.insert_all(0, vec![&b""[..], b""]);
+            t.insert_all(t.len(), vec![&b"!"[..]]);
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello there, dear World!");
+            assert!(t.undo());
+            assert!(t.undo());
             assert_eq!(t.to_utf8_string().unwrap(), "Hello World");
-            t.insert(0, "...".as_bytes());
-            assert_eq!(t.len(), 14);
-            assert_eq!(t.to_utf8_string().unwrap(), "...Hello World");
+        } 
+
+        #[test]
+        fn split_off() {
+            let original = b"Hello World".to_vec();
+            for &borrowed in &[false, true] {
+                let mut t = if borrowed {
+                    Text::from_mmap(&original)
+                } else {
+                    Text::from(&original[..])
+                };
+                t.insert(5, b", dear");
+                t.delete(0, 1);
+                t.insert(0, b"J");
+                // "Jello, dear World"
+                let rest = t.split_off(8);
+                assert_eq!(t.to_utf8_string().unwrap(), "Jello, d");
+                assert_eq!(rest.to_utf8_string().unwrap(), "ear World");
+                assert_eq!(rest.buffer.len(), if borrowed { 3 } else { 9 });
+                let mut t2 = t.clone();
+                let all = t2.split_off(0);
+                assert!(t2.is_empty());
+                assert_eq!(all, t);
+                let none = t2.split_off(0);
+                assert!(none.is_empty());
+                let none = t.split_off(t.len());
+                assert!(none.is_empty());
+                assert_eq!(t.to_utf8_string().unwrap(), "Jello, d");
+                assert!(t.undo());
+                assert_eq!(t.to_utf8_string().unwrap(), "Jello, dear World");
+            } 
+        } 
+
+        #[test]
+        #[should_panic(expected = "past the end")]
+        fn insert_out_of_bounds_panics() {
+            let mut t = Text::from("abc");
+            t.insert(4, b"x");
         } 
 
         #[test]
@@ -478,6 +6159,563 @@ mod tests {
             assert_eq!(t.to_utf8_string().unwrap(), "16");
         } 
 
+        #[test]
+        fn replace() {
+            let mut t = Text::new();
+            t.insert(0, "456".as_bytes());
+            t.insert(0, "123".as_bytes());
+            t.replace(2, 4, "xyz".as_bytes());
+            assert_eq!(t.len(), 7);
+            assert_eq!(t.to_utf8_string().unwrap(), "12xyz56");
+            t.replace(0, 7, "a".as_bytes());
+            assert_eq!(t.to_utf8_string().unwrap(), "a");
+        } 
+
+        #[test]
+        fn replace_insert() {
+            let mut t = Text::new();
+            t.insert(0, "1234".as_bytes());
+            t.replace(2, 2, "x".as_bytes());
+            assert_eq!(t.to_utf8_string().unwrap(), "12x34");
+            t.replace(5, 5, "y".as_bytes());
+            assert_eq!(t.to_utf8_string().unwrap(), "12x34y");
+            t.replace(0, 0, "z".as_bytes());
+            assert_eq!(t.to_utf8_string().unwrap(), "z12x34y");
+        } 
+
+        #[test]
+        fn replace_delete() {
+            let mut t = Text::new();
+            t.insert(0, "456".as_bytes());
+            t.insert(0, "123".as_bytes());
+            t.replace(1, 5, "".as_bytes());
+            assert_eq!(t.len(), 2);
+            assert_eq!(t.to_utf8_string().unwrap(), "16");
+        } 
+
+        #[test]
+        fn undo_redo() {
+            let mut t = Text::new();
+            assert!(!t.undo());
+            t.insert(0, "1234".as_bytes());
+            t.insert(2, "x".as_bytes());
+            t.delete(0, 2);
+            assert_eq!(t.to_utf8_string().unwrap(), "x34");
+            assert!(t.undo());
+            assert_eq!(t.to_utf8_string().unwrap(), "12x34");
+            assert!(t.undo());
+            assert_eq!(t.to_utf8_string().unwrap(), "1234");
+            assert!(t.redo());
+            assert_eq!(t.to_utf8_string().unwrap(), "12x34");
+            assert!(t.redo());
+            assert_eq!(t.to_utf8_string().unwrap(), "x34");
+            assert!(!t.redo());
+            assert!(t.undo());
+            assert!(t.undo());
+            assert!(t.undo());
+            assert_eq!(t.len(), 0);
+            assert!(!t.undo());
+        } 
+
+        #[test]
+        fn edit_clears_redo() {
+            let mut t = Text::new();
+            t.insert(0, "1234".as_bytes());
+            t.replace(1, 3, "x".as_bytes());
+            assert!(t.undo());
+            t.insert(0, "y".as_bytes());
+            assert!(!t.redo());
+            assert_eq!(t.to_utf8_string().unwrap(), "y1234");
+            assert!(t.undo());
+            assert_eq!(t.to_utf8_string().unwrap(), "1234");
+        } 
+
+        #[test]
+        fn lines() {
+            let mut t = Text::new();
+            assert_eq!(t.line_of_offset(0), 0);
+            assert_eq!(t.offset_of_line(0), Some(0));
+            assert_eq!(t.offset_of_line(1), None);
+            t.insert(0, "c\nd".as_bytes());
+            t.insert(0, "a\nb\n".as_bytes());
+            t.insert(3, "x\ny".as_bytes());
+            assert_eq!(t.to_utf8_string().unwrap(), "a\nbx\ny\nc\nd");
+            assert_eq!(t.line_of_offset(0), 0);
+            assert_eq!(t.line_of_offset(1), 0);
+            assert_eq!(t.line_of_offset(2), 1);
+            assert_eq!(t.line_of_offset(4), 1);
+            assert_eq!(t.line_of_offset(5), 2);
+            assert_eq!(t.line_of_offset(8), 3);
+            assert_eq!(t.line_of_offset(10), 4);
+            assert_eq!(t.line_of_offset(11), 4);
+            assert_eq!(t.offset_of_line(1), Some(2));
+            assert_eq!(t.offset_of_line(2), Some(5));
+            assert_eq!(t.offset_of_line(3), Some(7));
+            assert_eq!(t.offset_of_line(4), Some(9));
+            assert_eq!(t.offset_of_line(5), None);
+            t.delete(1, 6);
+            assert_eq!(t.to_utf8_string().unwrap(), "a\nc\nd");
+            assert_eq!(t.line_of_offset(3), 1);
+            assert_eq!(t.offset_of_line(2), Some(4));
+            assert_eq!(t.offset_of_line(3), None);
+        } 
+
+        #[test]
+        fn lines_iter() {
+            let lines = |s: &str| {
+                let mut t = Text::new();
+                t.insert(0, s.as_bytes());
+                t.lines().map(|l| String::from_utf8(l).unwrap()).collect::<Vec<_>>()
+            };
+            assert_eq!(lines(""), Vec::<String>::new());
+            assert_eq!(lines("a\nb\n"), vec!["a", "b"]);
+            assert_eq!(lines("a\nb"), vec!["a", "b"]);
+            assert_eq!(lines("a\n\n"), vec!["a", ""]);
+            assert_eq!(lines("\n"), vec![""]);
+            let mut t = Text::new();
+            t.insert(0, "cd\ne".as_bytes());
+            t.insert(0, "a\nb".as_bytes());
+            assert_eq!(t.lines().collect::<Vec<_>>(), vec![b"a".to_vec(), b"bcd".to_vec(), b"e".to_vec()]);
+        } 
+
+        #[test]
+        fn line_ranges() {
+            let ranges = |s: &str| Text::from(s).line_ranges().collect::<Vec<_>>();
+            assert!(ranges("").is_empty());
+            assert_eq!(ranges("a\nbc\n"), vec![(0, Span::new(0, 1)), (1, Span::new(2, 4))]);
+            assert_eq!(ranges("a\nbc"), vec![(0, Span::new(0, 1)), (1, Span::new(2, 4))]);
+            assert_eq!(ranges("a\n\n"), vec![(0, Span::new(0, 1)), (1, Span::new(2, 2))]);
+            assert_eq!(ranges("\n"), vec![(0, Span::new(0, 0))]);
+            let mut t = Text::new();
+            t.insert(0, "cd\ne\n\nf".as_bytes());
+            t.insert(0, "a\nb".as_bytes());
+            let lines = t.lines().collect::<Vec<_>>();
+            let ranges = t.line_ranges().collect::<Vec<_>>();
+            assert_eq!(ranges.len(), lines.len());
+            for (&(n, span), line) in ranges.iter().zip(lines.iter()) {
+                assert_eq!(&t.slice(span.off1, span.off2), line);
+                assert_eq!(t.line_of_offset(span.off1), n);
+            } 
+        } 
+
+        #[test]
+        fn find() {
+            let mut t = Text::new();
+            assert_eq!(t.find("".as_bytes()), Some(0));
+            assert_eq!(t.find("a".as_bytes()), None);
+            t.insert(0, "lo World, Hello".as_bytes());
+            t.insert(0, "Hel".as_bytes());
+            assert_eq!(t.find("Hello".as_bytes()), Some(0));
+            assert_eq!(t.find("World".as_bytes()), Some(6));
+            assert_eq!(t.find("Worlds".as_bytes()), None);
+            assert_eq!(t.find_from("Hello".as_bytes(), 1), Some(13));
+            assert_eq!(t.find_from("Hello".as_bytes(), 14), None);
+            assert_eq!(t.find_from("".as_bytes(), 18), Some(18));
+            assert_eq!(t.find_from("".as_bytes(), 19), None);
+            t.insert(0, "aab".as_bytes());
+            t.insert(0, "aa".as_bytes());
+            assert_eq!(t.find("aaab".as_bytes()), Some(1));
+        } 
+
+        #[test]
+        fn find_iter() {
+            let t = Text::from("aaaa");
+            assert_eq!(t.find_iter(b"aa").collect::<Vec<_>>(), vec![0, 2]);
+            assert_eq!(t.find_iter(b"aaa").collect::<Vec<_>>(), vec![0]);
+            assert_eq!(t.find_iter(b"b").count(), 0);
+            assert_eq!(t.find_iter(b"").collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+            let mut t = Text::from("one two one");
+            t.insert(3, b" one");
+            // "one one two one", the second "one" on a piece of its own
+            assert_eq!(t.find_iter(b"one").collect::<Vec<_>>(), vec![0, 4, 12]);
+            assert_eq!(t.find_iter(b"ne o").collect::<Vec<_>>(), vec![1]);
+        } 
+
+        #[test]
+        fn rfind() {
+            let mut t = Text::from("abcab");
+            assert_eq!(t.rfind(b""), Some(5));
+            assert_eq!(t.rfind(b"ab"), Some(3));
+            assert_eq!(t.rfind(b"ca"), Some(2));
+            assert_eq!(t.rfind(b"abcab"), Some(0));
+            assert_eq!(t.rfind(b"abcabc"), None);
+            assert_eq!(t.rfind(b"x"), None);
+            t.insert(4, b"xab");
+            t.insert(0, b"a");
+            // "aabcaxabb"
+            assert_eq!(t.rfind(b"ab"), Some(6));
+            assert_eq!(t.rfind(b"bb"), Some(7));
+            assert_eq!(t.rfind(b"aa"), Some(0));
+            for needle in &[&b"a"[..], b"ab", b"b", b"ca"] {
+                assert_eq!(t.rfind(needle), t.find_iter(needle).max());
+            } 
+        } 
+
+        #[test]
+        fn find_ignore_ascii_case() {
+            let mut t = Text::from("Grüße, hELLO wORLD");
+            assert_eq!(t.find_ignore_ascii_case(b""), Some(0));
+            assert_eq!(t.find_ignore_ascii_case(b"hello"), Some(9));
+            // split the match between pieces
+            t.insert(12, b"!");
+            t.insert(9, b">");
+            assert_eq!(t.find_ignore_ascii_case(b"WORLD"), Some(17));
+            assert_eq!(t.find_ignore_ascii_case(b"hello"), None);
+            assert_eq!(t.find_ignore_ascii_case(b">HeL"), Some(9));
+            // non ASCII bytes match only themselves
+            assert_eq!(t.find_ignore_ascii_case("GRÜ".as_bytes()), None);
+            assert_eq!(t.find_ignore_ascii_case("grü".as_bytes()), Some(0));
+            assert_eq!(t.find_ignore_ascii_case("ÜSSE".as_bytes()), None);
+        } 
+
+        #[test]
+        fn from() {
+            let t = Text::from("Hello World");
+            assert_eq!(t.len(), 11);
+            assert_eq!(t.pieces().count(), 1);
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello World");
+            let mut t = Text::from(vec![1, 2, 3]);
+            t.insert(1, &[4]);
+            assert_eq!(t.to_vec(), vec![1, 4, 2, 3]);
+            assert!(t.undo());
+            assert_eq!(t.to_vec(), vec![1, 2, 3]);
+            assert!(!t.undo());
+            let t = Text::from(&[5, 6][..]);
+            assert_eq!(t.to_vec(), vec![5, 6]);
+            let t = Text::from("");
+            assert_eq!(t.len(), 0);
+            assert_eq!(t.pieces().count(), 0);
+        } 
+
+        #[test]
+        fn from_mmap() {
+            let original = "Hello World\nSecond line".as_bytes().to_vec();
+            let mut t = Text::from_mmap(&original);
+            assert_eq!(t.len(), 23);
+            assert_eq!(t.buffer.len(), 0);
+            t.insert(5, ",".as_bytes());
+            t.delete(13, 19);
+            t.insert(0, ">".as_bytes());
+            assert_eq!(t.to_utf8_string().unwrap(), ">Hello, World\n line");
+            // only the inserted bytes were copied
+            assert_eq!(t.buffer.len(), 2);
+            assert_eq!(t.byte_at(1), Some(b'H'));
+            assert_eq!(t.byte_at(6), Some(b','));
+            assert_eq!(t.slice(4, 10), "lo, Wo".as_bytes());
+            assert_eq!(t.line_of_offset(15), 1);
+            assert_eq!(t.bytes_rev().next(), Some(b'e'));
+            assert_eq!(t.coalesce(), 0);
+            assert!(t.undo());
+            assert!(t.undo());
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello, World\nSecond line");
+        } 
+
+        #[test]
+        fn clone() {
+            let mut t = Text::new();
+            t.insert(0, "World".as_bytes());
+            t.insert(0, "Hello ".as_bytes());
+            let mut c = t.clone();
+            c.delete(0, 6);
+            c.insert(5, "!".as_bytes());
+            assert_eq!(c.to_utf8_string().unwrap(), "World!");
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello World");
+            t.undo();
+            assert_eq!(t.to_utf8_string().unwrap(), "World");
+            assert_eq!(c.to_utf8_string().unwrap(), "World!");
+        } 
+
+        #[test]
+        fn markers() {
+            let mut t = Text::from("0123456789");
+            let m0 = t.add_marker(0);
+            let m3 = t.add_marker(3);
+            let m5 = t.add_marker(5);
+            let m10 = t.add_marker(10);
+            t.insert(3, "ab".as_bytes());
+            assert_eq!(t.marker_offset(m0), 0);
+            assert_eq!(t.marker_offset(m3), 5);
+            assert_eq!(t.marker_offset(m5), 7);
+            assert_eq!(t.marker_offset(m10), 12);
+            t.delete(1, 6);
+            assert_eq!(t.to_utf8_string().unwrap(), "0456789");
+            assert_eq!(t.marker_offset(m0), 0);
+            assert_eq!(t.marker_offset(m3), 1);
+            assert_eq!(t.marker_offset(m5), 2);
+            assert_eq!(t.marker_offset(m10), 7);
+            t.replace(1, 3, "xyz".as_bytes());
+            assert_eq!(t.marker_offset(m3), 4);
+            assert_eq!(t.marker_offset(m5), 4);
+            assert_eq!(t.marker_offset(m10), 8);
+            t.undo();
+            t.undo();
+            assert_eq!(t.marker_offset(m0), 0);
+            assert_eq!(t.marker_offset(m10), 12);
+            t.redo();
+            assert_eq!(t.marker_offset(m0), 0);
+            assert_eq!(t.marker_offset(m10), 7);
+        } 
+
+        #[test]
+        fn snapshot() {
+            let mut t = Text::from("Hello World");
+            let s0 = t.snapshot();
+            assert!(t.restore(&s0));
+            t.insert(5, ",".as_bytes());
+            t.delete(0, 1);
+            let s1 = t.snapshot();
+            t.insert(0, "J".as_bytes());
+            assert_eq!(t.to_utf8_string().unwrap(), "Jello, World");
+            assert!(t.restore(&s0));
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello World");
+            assert!(t.restore(&s1));
+            assert_eq!(t.to_utf8_string().unwrap(), "ello, World");
+            t.undo();
+            t.insert(0, "x".as_bytes());
+            assert!(!t.restore(&s1));
+            assert_eq!(t.to_utf8_string().unwrap(), "xHello, World");
+            assert!(t.restore(&s0));
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello World");
+        } 
+
+        #[test]
+        fn changes_since() {
+            let mut t = Text::from("Hello World");
+            t.insert(0, b">> ");
+            let s = t.snapshot();
+            let saved = t.clone();
+            assert_eq!(t.changes_since(&s), Some(Vec::new()));
+            t.insert(8, b",");
+            t.replace(10, 15, b"there");
+            t.coalesce();
+            t.delete(0, 3);
+            let edits = t.changes_since(&s).unwrap();
+            assert_eq!(edits, vec![Edit::Insert { off: 8, bytes: b",".to_vec() },
+                                   Edit::Delete { off: 10, len: 5 },
+                                   Edit::Insert { off: 10, bytes: b"there".to_vec() },
+                                   Edit::Delete { off: 0, len: 3 }]);
+            let mut replayed = saved;
+            for e in edits {
+                match e {
+                    Edit::Insert { off, bytes } => replayed.insert(off, &bytes),
+                    Edit::Delete { off, len } => replayed.delete(off, off + len),
+                } 
+            } 
+            assert_eq!(replayed, t);
+            assert!(t.undo());
+            assert_eq!(t.changes_since(&s).unwrap().len(), 3);
+            let ahead = t.snapshot();
+            assert!(t.undo());
+            assert_eq!(t.changes_since(&ahead), None);
+        } 
+
+        #[test]
+        fn changes_since_misses_set_byte() {
+            let mut t = Text::new();
+            t.append(b"abc");
+            let s = t.snapshot();
+            assert!(t.set_byte(1, b'x'));
+            assert_eq!(t.to_vec(), b"axc");
+            assert_eq!(t.changes_since(&s), Some(Vec::new()));
+            t.insert(0, b"12");
+            assert!(t.set_byte(0, b'0'));
+            assert_eq!(t.changes_since(&s), Some(vec![Edit::Insert { off: 0, bytes: b"02".to_vec() }]));
+        } 
+
+        #[test]
+        fn coalesce() {
+            let mut t = Text::new();
+            assert_eq!(t.coalesce(), 0);
+            t.append("abc".as_bytes());
+            t.insert(1, "x".as_bytes());
+            t.insert(3, "y".as_bytes());
+            t.insert(0, "z".as_bytes());
+            t.delete(2, 3);
+            t.delete(3, 4);
+            assert_eq!(t.pieces().count(), 4);
+            assert_eq!(t.coalesce(), 2);
+            assert_eq!(t.pieces().count(), 2);
+            assert_eq!(t.to_utf8_string().unwrap(), "zabc");
+            assert_eq!(t.coalesce(), 0);
+            assert!(t.undo());
+            assert_eq!(t.to_utf8_string().unwrap(), "zabyc");
+            assert!(t.redo());
+            assert_eq!(t.to_utf8_string().unwrap(), "zabc");
+            assert_eq!(t.pieces().count(), 2);
+        } 
+
+        #[test]
+        fn push() {
+            let mut t = Text::new();
+            for word in ["Hello", ",", " ", "World"].iter() {
+                t.push_str(word);
+            } 
+            t.push(b"!");
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello, World!");
+            assert_eq!(t.pieces().count(), 1);
+            let mut t = Text::from("abc");
+            t.push(b"");
+            assert_eq!(t.pieces().count(), 1);
+            for _ in 0..100 {
+                t.push(b"d");
+            } 
+            assert_eq!(t.len(), 103);
+            assert_eq!(t.pieces().count(), 2);
+            assert!(t.undo());
+            assert_eq!(t.len(), 102);
+        } 
+
+        #[test]
+        fn appends_extend_the_last_piece() {
+            let mut t = Text::from("Hello");
+            for &b in b" World\n!" {
+                t.append(&[b]);
+            } 
+            assert_eq!(t.pieces().count(), 2);
+            assert_eq!(t.line_of_offset(13), 1);
+            // typing in the middle
+            t.insert(5, b",");
+            t.insert(6, b" dear");
+            assert_eq!(t.pieces().count(), 3);
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello, dear World\n!");
+            assert!(t.undo());
+            assert!(t.undo());
+            assert!(t.undo());
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello World\n");
+            assert!(t.redo());
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello World\n!");
+            // not after a piece from the buffer
+            let mut t = Text::from("ab");
+            t.insert(2, b"c");
+            t.insert(1, b"x");
+            t.insert(4, b"d");
+            assert_eq!(t.to_utf8_string().unwrap(), "axbcd");
+            assert_eq!(t.pieces().count(), 5);
+        } 
+
+        #[test]
+        fn coalesce_after_delete() {
+            let mut t = Text::from("Hello World");
+            t.insert(5, ",".as_bytes());
+            t.delete(5, 6);
+            assert_eq!(t.pieces().count(), 2);
+            assert_eq!(t.coalesce(), 1);
+            assert_eq!(t.pieces().count(), 1);
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello World");
+            let s = t.snapshot();
+            assert!(t.undo());
+            assert_eq!(t.to_utf8_string().unwrap(), "Hello, World");
+            assert!(t.restore(&s));
+            assert_eq!(t.pieces().count(), 1);
+        } 
+
+        #[test]
+        fn compact() {
+            let mut t = Text::from("Hello World");
+            t.insert(5, ", cruel".as_bytes());
+            t.insert(0, "Oh, ".as_bytes());
+            let m = t.add_marker(9);
+            let s = t.snapshot();
+            t.delete(9, 16);
+            t.append("!!".as_bytes());
+            assert_eq!(t.buffer.len(), 13);
+            assert_eq!(t.compact(), 7);
+            assert_eq!(t.buffer.len(), 6);
+            assert_eq!(t.pieces.len(), t.pieces().count() + 1);
+            assert_eq!(t.to_utf8_string().unwrap(), "Oh, Hello World!!");
+            assert_eq!(t.marker_offset(m), 9);
+            // the history is gone
+            assert!(!t.undo());
+            assert!(!t.restore(&s));
+            assert_eq!(t.to_utf8_string().unwrap(), "Oh, Hello World!!");
+            assert_eq!(t.compact(), 0);
+            t.insert(2, "h".as_bytes());
+            assert_eq!(t.to_utf8_string().unwrap(), "Ohh, Hello World!!");
+            assert!(t.undo());
+            assert_eq!(t.to_utf8_string().unwrap(), "Oh, Hello World!!");
+            assert_eq!(Text::new().compact(), 0);
+        } 
+
+        #[test]
+        fn auto_compact() {
+            for &policy in [CompactPolicy::Off, CompactPolicy::Auto].iter() {
+                let mut rng = Rng::new(7);
+                let mut t = Text::from("Hello World");
+                t.set_auto_compact(policy);
+                let mut v = t.to_vec();
+                let mut most = 0;
+                for i in 0..2000 {
+                    let off = rng.below(t.len() + 1);
+                    if rng.below(2) == 0 || t.len() < 10 {
+                        t.insert(off, &[b'a' + (i % 26) as u8]);
+                        v.insert(index(off), b'a' + (i % 26) as u8);
+                    } else {
+                        let off = std::cmp::min(off, t.len() - 1);
+                        t.delete(off, off + 1);
+                        v.remove(index(off));
+                    } 
+                    most = std::cmp::max(most, t.stats().total_pieces);
+                } 
+                assert_eq!(t.to_vec(), v);
+                // the live pieces stay few, so without compaction the
+                // pieces vector only grows
+                if policy == CompactPolicy::Off {
+                    assert!(most > 1000);
+                    assert!(t.undo());
+                } else {
+                    assert!(most < 2 * AUTO_COMPACT_MIN_PIECES);
+                } 
+            } 
+        } 
+
+        #[test]
+        fn auto_compact_in_transaction() {
+            let mut t = Text::from("Hello World");
+            t.set_auto_compact(CompactPolicy::Auto);
+            for commit in [false, true].iter() {
+                let before = t.to_vec();
+                let pieces = t.pieces.len();
+                let mut tr = t.begin();
+                for i in 0..1000 {
+                    tr.insert(i % 7, b"x");
+                    tr.delete(i % 5, i % 5 + 1);
+                } 
+                assert!(tr.pieces.len() > pieces + 1000);
+                let after = tr.to_vec();
+                if *commit {
+                    tr.commit();
+                    assert_eq!(t.to_vec(), after);
+                } else {
+                    drop(tr);
+                    assert_eq!(t.to_vec(), before);
+                } 
+            } 
+            // compacting again once the transactions are done
+            t.insert(0, b"y");
+            assert!(t.pieces.len() < 2 * AUTO_COMPACT_MIN_PIECES);
+            assert!(t.undo());
+            assert!(!t.undo());
+        } 
+
+        #[test]
+        fn stats() {
+            let mut t = Text::from("Hello World");
+            t.insert(5, ", cruel".as_bytes());
+            t.delete(5, 7);
+            let s = t.stats();
+            assert_eq!(s.logical_len, 16);
+            assert_eq!(s.buffer_used, 7);
+            assert!(s.buffer_capacity >= 7);
+            assert_eq!(s.live_pieces, 3);
+            // sentinel, original, its two halves, ", cruel", "cruel"
+            assert_eq!(s.total_pieces, 6);
+            assert_eq!(s.dead_bytes, 2);
+            t.compact();
+            let s = t.stats();
+            assert_eq!(s.dead_bytes, 0);
+            assert_eq!(s.total_pieces, 4);
+        } 
+
         #[test]
         fn bytes1() {
             let mut t = Text::new();
@@ -495,6 +6733,205 @@ mod tests {
             t.insert(0, &bytes);
             assert_eq!(t.bytes().collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
         } 
+
+        #[test]
+        fn bytes_size_hint() {
+            let mut t = Text::new();
+            assert_eq!(t.bytes().size_hint(), (0, Some(0)));
+            assert_eq!(t.bytes().next(), None);
+            t.insert(0, &[3, 4]);
+            t.insert(0, &[0, 1, 2]);
+            let mut b = t.bytes();
+            assert_eq!(b.len(), 5);
+            b.next();
+            b.next();
+            b.next();
+            assert_eq!(b.size_hint(), (2, Some(2)));
+            assert_eq!(b.next(), Some(3));
+        } 
+
+        #[test]
+        fn bytes_many_pieces() {
+            let mut t = Text::new();
+            for _ in 0..1000 {
+                t.append(&[7]);
+            } 
+            assert_eq!(t.bytes().count(), 1000);
+        } 
+
+        #[test]
+        fn slice() {
+            let mut t = Text::new();
+            t.insert(0, "789".as_bytes());
+            t.insert(0, "456".as_bytes());
+            t.insert(0, "123".as_bytes());
+            assert_eq!(t.slice(0, 9), "123456789".as_bytes());
+            assert_eq!(t.slice(1, 8), "2345678".as_bytes());
+            assert_eq!(t.slice(3, 6), "456".as_bytes());
+            assert_eq!(t.slice(4, 5), "5".as_bytes());
+            assert_eq!(t.slice(2, 4), "34".as_bytes());
+            assert!(t.slice(9, 9).is_empty());
+            assert!(t.slice(5, 2).is_empty());
+        } 
+
+        #[test]
+        fn counts_in_range() {
+            let mut t = Text::new();
+            t.insert(0, "b\nä\n".as_bytes());
+            t.insert(0, "a\nb".as_bytes());
+            // "a\nbb\nä\n", ä is two bytes
+            assert_eq!(t.count_in_range(0, 9, b'\n'), 3);
+            assert_eq!(t.count_in_range(1, 5, b'\n'), 2);
+            assert_eq!(t.count_in_range(2, 4, b'b'), 2);
+            assert_eq!(t.count_in_range(2, 100, b'b'), 2);
+            assert_eq!(t.count_in_range(4, 4, b'b'), 0);
+            assert_eq!(t.count_in_range(5, 1, b'\n'), 0);
+            assert_eq!(t.char_count_in_range(0, 9), 7);
+            assert_eq!(t.char_count_in_range(5, 7), 1);
+            assert_eq!(t.char_count_in_range(7, 5), 0);
+            assert_eq!(t.line_count_in_range(0, 9), 3);
+            assert_eq!(t.line_count_in_range(0, 8), 3);
+            assert_eq!(t.line_count_in_range(2, 5), 1);
+            assert_eq!(t.line_count_in_range(2, 6), 2);
+            assert_eq!(t.line_count_in_range(1, 2), 1);
+            assert_eq!(t.line_count_in_range(3, 3), 0);
+            assert_eq!(t.line_count_in_range(3, 1), 0);
+        } 
+
+        #[test]
+        fn line() {
+            let mut t = Text::from("one\ntwo\r\nthree");
+            t.insert(5, b"w\nt");
+            t.append(b"\n");
+            t.insert(0, b"zero\n");
+            // "zero\none\ntw\ntwo\r\nthree\n" in five pieces
+            let lines = (0..7).map(|n| t.line(n)).collect::<Vec<_>>();
+            assert_eq!(lines, vec![Some(b"zero".to_vec()), Some(b"one".to_vec()),
+                                   Some(b"tw".to_vec()), Some(b"two\r".to_vec()),
+                                   Some(b"three".to_vec()), Some(Vec::new()), None]);
+            assert_eq!(t.line_count(), 6);
+            assert_eq!(Text::new().line(0), Some(Vec::new()));
+            assert_eq!(Text::new().line(1), None);
+        } 
+
+        #[test]
+        fn line_count() {
+            let mut t = Text::new();
+            assert_eq!(t.line_count(), 1);
+            t.insert(0, b"a");
+            assert_eq!(t.line_count(), 1);
+            t.append(b"\n");
+            assert_eq!(t.line_count(), 2);
+            assert_eq!(t.line_of_offset(t.len()), 1);
+            t.append(b"b\nc");
+            t.insert(1, b"\n\n");
+            // "a\n\n\nb\nc"
+            assert_eq!(t.line_count(), 5);
+            t.delete(2, 5);
+            // "a\n\nc", the delete split pieces
+            assert_eq!(t.line_count(), 3);
+            assert_eq!(t.line_count(), t.bytes().filter(|&b| b == b'\n').count() as Offset + 1);
+            assert!(t.undo());
+            assert_eq!(t.line_count(), 5);
+        } 
+
+        #[test]
+        fn is_char_boundary() {
+            let mut t = Text::from("a€");
+            t.append(b"b");
+            assert!(t.is_char_boundary(0));
+            assert!(t.is_char_boundary(1));
+            assert!(!t.is_char_boundary(2));
+            assert!(!t.is_char_boundary(3));
+            assert!(t.is_char_boundary(4));
+            assert!(t.is_char_boundary(5));
+            assert!(!t.is_char_boundary(6));
+            assert!(Text::new().is_char_boundary(0));
+        } 
+
+        #[test]
+        fn insert_str_and_delete_chars() {
+            let mut t = Text::from("caf");
+            t.append("é!".as_bytes());
+            assert_eq!(t.insert_str(4, "x"), Err(NotCharBoundary { off: 4 }));
+            assert_eq!(t.insert_str(8, "x"), Err(NotCharBoundary { off: 8 }));
+            assert_eq!(t.to_utf8_string().unwrap(), "café!");
+            assert_eq!(t.insert_str(5, "\u{301}"), Ok(()));
+            assert_eq!(t.insert_str(0, "ç"), Ok(()));
+            assert_eq!(t.to_utf8_string().unwrap(), "çcafé\u{301}!");
+            t.delete_chars(4, 6);
+            assert_eq!(t.to_utf8_string().unwrap(), "çcaf!");
+            t.delete_chars(0, 1);
+            t.delete_chars(3, 3);
+            assert_eq!(t.to_utf8_string().unwrap(), "caf!");
+            t.delete_chars(1, 4);
+            assert_eq!(t.to_utf8_string().unwrap(), "c");
+        } 
+
+        #[test]
+        fn insert_at_char() {
+            let mut t = Text::from("añ");
+            t.append("€b".as_bytes());
+            assert_eq!(t.insert_at_char(2, "x"), Ok(()));
+            assert_eq!(t.to_utf8_string().unwrap(), "añx€b");
+            assert_eq!(t.insert_at_char(0, "¡"), Ok(()));
+            assert_eq!(t.insert_at_char(6, "!"), Ok(()));
+            assert_eq!(t.to_utf8_string().unwrap(), "¡añx€b!");
+            assert_eq!(t.insert_at_char(8, "?"), Err(OffsetError::OutOfBounds { off: 8, len: 7 }));
+            assert_eq!(t.to_utf8_string().unwrap(), "¡añx€b!");
+        } 
+
+        #[test]
+        #[should_panic(expected = "past the end")]
+        fn delete_chars_out_of_range() {
+            let mut t = Text::from("€€");
+            t.delete_chars(1, 3);
+        } 
+
+        #[test]
+        fn char_indices_around() {
+            let mut t = Text::from("a€");
+            t.append("b\u{1F600}".as_bytes());
+            // a at 0, € at 1..4, b at 4, the emoji at 5..9
+            assert_eq!(t.char_indices_around(0), (None, Some(1)));
+            assert_eq!(t.char_indices_around(1), (Some(0), Some(4)));
+            assert_eq!(t.char_indices_around(2), (Some(1), Some(4)));
+            assert_eq!(t.char_indices_around(4), (Some(1), Some(5)));
+            assert_eq!(t.char_indices_around(5), (Some(4), Some(9)));
+            assert_eq!(t.char_indices_around(9), (Some(5), None));
+            let t = Text::from(&[0x80, 0x80, 0x80, 0x80, 0x80, b'a'][..]);
+            assert_eq!(t.char_indices_around(0), (None, Some(4)));
+            assert_eq!(t.char_indices_around(5), (Some(1), Some(6)));
+            assert_eq!(Text::new().char_indices_around(0), (None, None));
+        } 
+
+        #[test]
+        fn byte_at() {
+            let mut t = Text::new();
+            assert_eq!(t.byte_at(0), None);
+            t.insert(0, "456".as_bytes());
+            t.insert(0, "123".as_bytes());
+            assert_eq!(t.byte_at(0), Some(b'1'));
+            assert_eq!(t.byte_at(2), Some(b'3'));
+            assert_eq!(t.byte_at(3), Some(b'4'));
+            assert_eq!(t.byte_at(5), Some(b'6'));
+            assert_eq!(t.byte_at(6), None);
+            assert_eq!(t.byte_at(7), None);
+        } 
+
+        #[test]
+        fn byte_before() {
+            let mut t = Text::new();
+            assert_eq!(t.byte_before(0), None);
+            t.insert(0, b"45");
+            t.insert(0, b"123");
+            assert_eq!(t.byte_before(0), None);
+            assert_eq!(t.byte_before(1), Some(b'1'));
+            assert_eq!(t.byte_before(3), Some(b'3'));
+            assert_eq!(t.byte_before(4), Some(b'4'));
+            assert_eq!(t.byte_before(5), Some(b'5'));
+            assert_eq!(t.byte_before(6), None);
+        } 
     } 
 }
 
@@ -515,7 +6952,6 @@ impl Command {
     } 
 } 
 
-#[cfg(not(test))]
 fn main() {
     env_logger::init().unwrap();
     info!("starting up");